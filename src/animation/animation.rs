@@ -5,12 +5,16 @@ use rand::prelude::StdRng;
 use rand::Rng;
 use rand::SeedableRng;
 
+use crate::animation::camera_track::CameraTrack;
 use crate::raytracer::camera::{Camera, CameraRange};
 use crate::raytracer::color::{blend_colors, Color};
+use crate::raytracer::integrator::IntegratorKind;
 use crate::raytracer::material::Material;
-use crate::raytracer::physics::PhysicsSettings;
-use crate::raytracer::render::Sky;
+use crate::raytracer::physics::{GravityApproximation, PhysicsSettings};
+use crate::raytracer::render::{Background, Sky};
+use crate::raytracer::quaternion::Quaternion;
 use crate::raytracer::sphere::Sphere;
+use crate::raytracer::texture::Texture;
 use crate::raytracer::vector3d::Vector3d;
 use crate::raytracer::world::World;
 
@@ -29,18 +33,26 @@ fn random_sphere(rng: &mut StdRng) -> Sphere {
         center,
         radius,
         material: Material {
-            albedo: Color {
+            albedo: Texture::Solid(Color {
                 r: rng.gen_range(0.0, 1.0),
                 g: rng.gen_range(0.0, 1.0),
                 b: rng.gen_range(0.0, 1.0),
-            },
+            }),
             reflectiveness: 1.0 + 0.0 * rng.gen_range(0.0, 1.0),
             reflection_fuzz: 0.0 + 0.0 * rng.gen_range(0.0, 1.0),
+            transparency: 0.0,
+            refraction_index: 1.5,
+            absorption: Color::black(),
+            emission: Color::black(),
+            flash_emission: 0.0,
         },
         speed: Vector3d::null(),
         mass: radius.powf(3.0),
         extra_brightness: 0.0,
         center_old: center,
+        angular_velocity: Vector3d::null(),
+        orientation: Quaternion::identity(),
+        orientation_old: Quaternion::identity(),
     }
 }
 
@@ -52,28 +64,41 @@ pub fn make_world() -> World {
         center,
         radius: radius_planet,
         material: Material {
-            albedo: Color { r: 0.5, g: 0.5, b: 0.5 },
+            albedo: Texture::Solid(Color { r: 0.5, g: 0.5, b: 0.5 }),
             reflectiveness: 0.75,
             reflection_fuzz: 0.08,
+            transparency: 0.0,
+            refraction_index: 1.5,
+            absorption: Color::black(),
+            emission: Color::black(),
+            flash_emission: 0.0,
         },
         speed: Vector3d::null(),
         mass: radius_planet.powf(3.0),
         extra_brightness: 0.0,
         center_old: center,
+        angular_velocity: Vector3d::null(),
+        orientation: Quaternion::identity(),
+        orientation_old: Quaternion::identity(),
     };
 
     let number_of_spheres = 80;
-    World {
-        spheres: (0..number_of_spheres).map(move |_| random_sphere(&mut rng))
-            .chain(std::iter::once(planet))
-            .collect()
-    }
+    World::new((0..number_of_spheres).map(move |_| random_sphere(&mut rng))
+        .chain(std::iter::once(planet))
+        .collect())
 }
 
-pub fn camera_range(t_real: f64, t_real_previous_frame: f64, aspect_ratio: f64) -> CameraRange {
-    CameraRange {
-        cam_a: cam(t_real, aspect_ratio),
-        cam_b: cam(t_real_previous_frame, aspect_ratio),
+pub fn camera_range(track: Option<&CameraTrack>, t_real: f64, t_real_previous_frame: f64,
+                    aspect_ratio: f64) -> CameraRange {
+    match track {
+        Some(track) => CameraRange {
+            cam_a: track.camera(t_real, aspect_ratio),
+            cam_b: track.camera(t_real_previous_frame, aspect_ratio),
+        },
+        None => CameraRange {
+            cam_a: cam(t_real, aspect_ratio),
+            cam_b: cam(t_real_previous_frame, aspect_ratio),
+        }
     }
 }
 
@@ -101,10 +126,6 @@ pub fn cam(t_real: f64, aspect_ratio: f64) -> Camera {
                 aspect_ratio, aperture, dist_to_focus)
 }
 
-pub fn num_frames() -> usize {
-    960
-}
-
 pub fn sky(t_real: f64) -> Sky {
     let sky_factor = t_real;
     let day1 = Color { r: 1.0, g: 1.0, b: 1.0 };
@@ -112,20 +133,29 @@ pub fn sky(t_real: f64) -> Sky {
     let night1 = Color { r: 0.8, g: 0.6, b: 0.7 };
     let night2 = Color { r: 0.4, g: 0.1, b: 0.15 };
     Sky {
-        col1: blend_colors(&night1, &day1, sky_factor),
-        col2: blend_colors(&night2, &day2, sky_factor),
+        background: Background::Gradient {
+            col1: blend_colors(&night1, &day1, sky_factor),
+            col2: blend_colors(&night2, &day2, sky_factor),
+        },
+        sun: None,
     }
 }
 
 pub fn physics_settings() -> PhysicsSettings {
     PhysicsSettings {
+        integrator: IntegratorKind::ExplicitEuler,
+        substeps: 1,
         gravity_constant: 0.73,
+        gravity_approximation: GravityApproximation::Exact,
         bounciness: 0.46,
         bounce_round_to_zero_threshold: 10.0,
         flash_strength: 0.006,
         dim_factor: 5.0,
         dim_constant: 1.32,
         friction: 12.1,
+        contact_friction: 0.4,
+        rolling_resistance: 2.0,
+        continuous_collision_detection: true,
     }
 }
 
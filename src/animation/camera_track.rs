@@ -0,0 +1,193 @@
+use crate::raytracer::camera::Camera;
+use crate::raytracer::vector3d::Vector3d;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Interpolation {
+    Linear,
+    CatmullRom,
+    // Cubic Bézier segments between consecutive keyframes, with handles along the velocity
+    // at each keyframe, so the movement is smooth even for unevenly spaced keyframes.
+    Bezier,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct CameraKeyframe {
+    // Real time in [0, 1], i.e., the fraction of the animation.
+    pub time: f64,
+    pub position: Vector3d,
+    pub looks_at: Vector3d,
+    #[serde(default = "default_up_direction")]
+    pub up_direction: Vector3d,
+    pub vertical_field_of_view: f64,
+    #[serde(default)]
+    pub aperture: f64,
+    // Defaults to the distance between `position` and `looks_at`.
+    pub focus_dist: Option<f64>,
+}
+
+/// Camera movement defined by keyframes sorted by time.
+#[derive(Debug, Deserialize)]
+pub struct CameraTrack {
+    pub interpolation: Interpolation,
+    pub keyframes: Vec<CameraKeyframe>,
+}
+
+fn default_up_direction() -> Vector3d {
+    Vector3d { x: 0.0, y: 1.0, z: 0.0 }
+}
+
+const NUM_PARAMETERS: usize = 13;
+
+type Parameters = [f64; NUM_PARAMETERS];
+
+impl CameraKeyframe {
+    fn parameters(&self) -> Parameters {
+        let focus_dist = self.focus_dist
+            .unwrap_or_else(|| (self.position - &self.looks_at).length());
+        [
+            self.position.x, self.position.y, self.position.z,
+            self.looks_at.x, self.looks_at.y, self.looks_at.z,
+            self.up_direction.x, self.up_direction.y, self.up_direction.z,
+            self.vertical_field_of_view, self.aperture, focus_dist,
+            self.time,
+        ]
+    }
+}
+
+fn camera_from_parameters(p: &Parameters, aspect_ratio: f64) -> Camera {
+    Camera::new(
+        &Vector3d { x: p[0], y: p[1], z: p[2] },
+        &Vector3d { x: p[3], y: p[4], z: p[5] },
+        &Vector3d { x: p[6], y: p[7], z: p[8] },
+        p[9], aspect_ratio, p[10], p[11])
+}
+
+fn combine(weighted: &[(f64, &Parameters)]) -> Parameters {
+    let mut result = [0.0; NUM_PARAMETERS];
+    for (weight, parameters) in weighted {
+        for (r, p) in result.iter_mut().zip(parameters.iter()) {
+            *r += weight * p;
+        }
+    }
+    result
+}
+
+fn lerp(a: &Parameters, b: &Parameters, u: f64) -> Parameters {
+    combine(&[(1.0 - u, a), (u, b)])
+}
+
+fn catmull_rom(p0: &Parameters, p1: &Parameters, p2: &Parameters, p3: &Parameters,
+               u: f64) -> Parameters {
+    let u2 = u * u;
+    let u3 = u2 * u;
+    combine(&[
+        (0.5 * (-u3 + 2.0 * u2 - u), p0),
+        (0.5 * (3.0 * u3 - 5.0 * u2 + 2.0), p1),
+        (0.5 * (-3.0 * u3 + 4.0 * u2 + u), p2),
+        (0.5 * (u3 - u2), p3),
+    ])
+}
+
+fn de_casteljau(control_points: &[Parameters], u: f64) -> Parameters {
+    let mut points = control_points.to_vec();
+    while points.len() > 1 {
+        points = points.windows(2).map(|w| lerp(&w[0], &w[1], u)).collect();
+    }
+    points[0]
+}
+
+impl CameraTrack {
+    // Change of the parameters per unit of time at keyframe `i`, from its neighbors
+    fn velocity(&self, keyframes: &[Parameters], i: usize) -> Parameters {
+        let (before, after) = (i.max(1) - 1, (i + 1).min(keyframes.len() - 1));
+        let delta_t = self.keyframes[after].time - self.keyframes[before].time;
+        combine(&[(1.0 / delta_t, &keyframes[after]), (-1.0 / delta_t, &keyframes[before])])
+    }
+
+    fn parameters_at(&self, t_real: f64) -> Parameters {
+        let keyframes: Vec<Parameters> = self.keyframes.iter()
+            .map(|k| k.parameters()).collect();
+        let first = self.keyframes.first().expect("Camera track without keyframes");
+        let last = self.keyframes.last().unwrap();
+        if keyframes.len() == 1 || t_real <= first.time {
+            return keyframes[0];
+        }
+        if t_real >= last.time {
+            return keyframes[keyframes.len() - 1];
+        }
+        let i = self.keyframes.iter().rposition(|k| k.time <= t_real).unwrap();
+        let (t0, t1) = (self.keyframes[i].time, self.keyframes[i + 1].time);
+        let u = (t_real - t0) / (t1 - t0);
+        match self.interpolation {
+            Interpolation::Linear => lerp(&keyframes[i], &keyframes[i + 1], u),
+            Interpolation::CatmullRom => catmull_rom(
+                &keyframes[i.max(1) - 1], &keyframes[i],
+                &keyframes[i + 1], &keyframes[(i + 2).min(keyframes.len() - 1)], u),
+            Interpolation::Bezier => {
+                let handle_length = (t1 - t0) / 3.0;
+                let (v0, v1) = (self.velocity(&keyframes, i), self.velocity(&keyframes, i + 1));
+                de_casteljau(&[
+                    keyframes[i],
+                    combine(&[(1.0, &keyframes[i]), (handle_length, &v0)]),
+                    combine(&[(1.0, &keyframes[i + 1]), (-handle_length, &v1)]),
+                    keyframes[i + 1],
+                ], u)
+            }
+        }
+    }
+
+    pub fn camera(&self, t_real: f64, aspect_ratio: f64) -> Camera {
+        camera_from_parameters(&self.parameters_at(t_real), aspect_ratio)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyframe(time: f64, x: f64) -> CameraKeyframe {
+        CameraKeyframe {
+            time,
+            position: Vector3d { x, y: 1.0, z: 10.0 },
+            looks_at: Vector3d::null(),
+            up_direction: default_up_direction(),
+            vertical_field_of_view: 60.0,
+            aperture: 0.0,
+            focus_dist: Some(10.0),
+        }
+    }
+
+    #[test]
+    fn test_interpolation_hits_keyframes() {
+        let keyframes = vec![keyframe(0.0, 0.0), keyframe(0.5, 4.0), keyframe(1.0, 2.0)];
+        let interpolations = [
+            Interpolation::Linear, Interpolation::CatmullRom, Interpolation::Bezier,
+        ];
+        for interpolation in &interpolations {
+            let track = CameraTrack { interpolation: *interpolation, keyframes: keyframes.clone() };
+            assert_eq!(track.parameters_at(0.5)[0], 4.0);
+            assert_eq!(track.parameters_at(1.0)[0], 2.0);
+        }
+        let linear = CameraTrack { interpolation: Interpolation::Linear, keyframes };
+        assert_eq!(linear.parameters_at(0.25)[0], 2.0);
+    }
+
+    #[test]
+    fn test_bezier() {
+        // Unevenly spaced, with a constant speed of 8 units per time between them.
+        let keyframes = vec![keyframe(0.0, 0.0), keyframe(0.25, 2.0), keyframe(1.0, 8.0)];
+        let track = CameraTrack { interpolation: Interpolation::Bezier, keyframes };
+        assert_eq!(track.parameters_at(0.25)[0], 2.0);
+        assert!((0..=20).all(|i| {
+            let t = i as f64 / 20.0;
+            (track.parameters_at(t)[0] - 8.0 * t).abs() < 1e-9
+        }));
+
+        // The speed does not jump at keyframes.
+        let keyframes = vec![keyframe(0.0, 0.0), keyframe(0.2, 4.0), keyframe(1.0, 2.0)];
+        let track = CameraTrack { interpolation: Interpolation::Bezier, keyframes };
+        let speed = |t: f64| (track.parameters_at(t + 1e-6)[0] - track.parameters_at(t)[0]) / 1e-6;
+        assert!((speed(0.2 - 1e-5) - speed(0.2)).abs() < 1e-2);
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::raytracer::sphere::Sphere;
+use crate::raytracer::world::World;
+
+/// Simulation state right before advancing the world to frame `frame_num`
+pub struct Checkpoint {
+    pub frame_num: usize,
+    pub world: World,
+    // Timeline the checkpoint was saved with, its frames only match the same one.
+    pub fps: f64,
+    pub num_frames: usize,
+}
+
+// Same format as `Checkpoint`, but without taking ownership of the world
+#[derive(Serialize)]
+struct CheckpointRef<'a> {
+    frame_num: usize,
+    world: &'a World,
+    fps: f64,
+    num_frames: usize,
+}
+
+// Same format as `CheckpointRef`, the images of the textures are loaded afterwards.
+#[derive(Deserialize)]
+struct CheckpointData {
+    frame_num: usize,
+    world: Vec<Sphere>,
+    fps: f64,
+    num_frames: usize,
+}
+
+/// `fps` and `num_frames` describe the timeline, so resuming can check that it did not change.
+pub fn save_checkpoint(path: &Path, frame_num: usize, world: &World, fps: f64, num_frames: usize) {
+    // Renamed when complete, so an interrupted run never leaves a truncated checkpoint.
+    let temp_path = path.with_extension("checkpoint.tmp");
+    {
+        let file = File::create(&temp_path)
+            .unwrap_or_else(|_| panic!("Unable to create checkpoint {}", temp_path.display()));
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, &CheckpointRef { frame_num, world, fps, num_frames })
+            .map_err(|e| e.to_string())
+            .and_then(|_| writer.flush().map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| panic!("Unable to write checkpoint {}: {}", path.display(), e));
+    }
+    fs::rename(&temp_path, path)
+        .unwrap_or_else(|_| panic!("Unable to save checkpoint {}", path.display()));
+}
+
+impl Checkpoint {
+    pub fn load(path: &Path) -> Checkpoint {
+        let file = File::open(path)
+            .unwrap_or_else(|_| panic!("Unable to open checkpoint {}", path.display()));
+        let mut data: CheckpointData = serde_json::from_reader(BufReader::new(file))
+            .unwrap_or_else(|e| panic!("Unable to read checkpoint {}: {}", path.display(), e));
+        // The paths have already been resolved when loading the scene.
+        let mut images = HashMap::new();
+        for sphere in data.world.iter_mut() {
+            sphere.material.albedo.load_image(Path::new(""), &mut images).unwrap_or_else(|e| {
+                panic!("Unable to load texture of checkpoint {}: {}", path.display(), e)
+            });
+        }
+        Checkpoint {
+            frame_num: data.frame_num,
+            world: World::new(data.world),
+            fps: data.fps,
+            num_frames: data.num_frames,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::animation::animation::{make_world, physics_settings};
+
+    use super::*;
+
+    #[test]
+    fn test_restored_simulation_continues_exactly() {
+        let physics = physics_settings();
+        let advance = |world: World, steps: usize| {
+            (0..steps).fold(world, |world, _| world.advance(0.01, &physics))
+        };
+        let world = advance(make_world(), 20);
+        let path = std::env::temp_dir()
+            .join(format!("bouncing_spheres_test_restore_{}.checkpoint", std::process::id()));
+        save_checkpoint(&path, 20, &world, 25.0, 100);
+        assert!(!path.with_extension("checkpoint.tmp").exists());
+        let restored = Checkpoint::load(&path);
+        assert_eq!((restored.frame_num, restored.fps, restored.num_frames), (20, 25.0, 100));
+
+        let expected = advance(world, 10);
+        let result = advance(restored.world, 10);
+        assert!(result.spheres().iter().zip(expected.spheres().iter()).all(|(a, b)| {
+            a.center == b.center && a.speed == b.speed && a.orientation == b.orientation
+        }));
+    }
+}
@@ -0,0 +1,57 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::animation::timeline::Animated;
+use crate::raytracer::environment_map::{EnvironmentLayer, EnvironmentMap};
+use crate::raytracer::render::Background;
+
+#[derive(Debug, Deserialize)]
+pub struct EnvironmentMapDescription {
+    // Radiance `.hdr` or `.pfm` file in equirectangular projection, relative to the scene file
+    pub path: PathBuf,
+    #[serde(default = "full_intensity")]
+    pub intensity: Animated,
+}
+
+/// Sky made of environment maps, e.g., a day and a night map with animated intensities
+#[derive(Debug, Deserialize)]
+pub struct EnvironmentDescription {
+    // Around the y-axis, in degrees
+    #[serde(default = "no_rotation")]
+    pub rotation: Animated,
+    pub maps: Vec<EnvironmentMapDescription>,
+}
+
+fn full_intensity() -> Animated {
+    Animated::Constant(1.0)
+}
+
+fn no_rotation() -> Animated {
+    Animated::Constant(0.0)
+}
+
+pub struct Environment {
+    rotation: Animated,
+    maps: Vec<(Arc<EnvironmentMap>, Animated)>,
+}
+
+impl Environment {
+    pub fn load(description: &EnvironmentDescription) -> Environment {
+        Environment {
+            rotation: description.rotation,
+            maps: description.maps.iter().map(|map| {
+                (Arc::new(EnvironmentMap::load(&map.path)), map.intensity)
+            }).collect(),
+        }
+    }
+
+    pub fn background(&self, t_real: f64) -> Background {
+        Background::Environment(self.maps.iter().map(|(map, intensity)| {
+            EnvironmentLayer {
+                map: map.clone(),
+                rotation: self.rotation.at(t_real).to_radians(),
+                intensity: intensity.at(t_real),
+            }
+        }).collect())
+    }
+}
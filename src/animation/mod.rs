@@ -1 +1,9 @@
+#[allow(clippy::module_inception)]
 pub mod animation;
+pub mod camera_track;
+pub mod checkpoint;
+pub mod environment;
+pub mod physical_sky;
+pub mod physics_cache;
+pub mod scene;
+pub mod timeline;
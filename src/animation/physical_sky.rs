@@ -0,0 +1,87 @@
+use crate::animation::timeline::Animated;
+use crate::raytracer::light::{default_sun_angular_radius, Sun};
+use crate::raytracer::physical_sky::PhysicalSky;
+use crate::raytracer::render::{Background, Sky};
+use crate::raytracer::vector3d::Vector3d;
+
+/// Analytic daylight sky with a sun moving over the animation, e.g., for a sunset
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct PhysicalSkyDescription {
+    // Above the horizon, in degrees
+    pub sun_elevation: Animated,
+    // Around the y-axis, starting at the x-axis, in degrees
+    #[serde(default = "zero")]
+    pub sun_azimuth: Animated,
+    // Haziness of the atmosphere, 2 (clear) to 10 (hazy)
+    #[serde(default = "default_turbidity")]
+    pub turbidity: Animated,
+    // Brightness of the sky
+    #[serde(default = "default_sky_intensity")]
+    pub intensity: f64,
+    // Irradiance of the sun before passing through the atmosphere
+    #[serde(default = "default_sun_irradiance")]
+    pub sun_irradiance: f64,
+    // In degrees
+    #[serde(default = "default_sun_angular_radius")]
+    pub sun_angular_radius: f64,
+}
+
+fn zero() -> Animated {
+    Animated::Constant(0.0)
+}
+
+fn default_turbidity() -> Animated {
+    Animated::Constant(3.0)
+}
+
+fn default_sky_intensity() -> f64 {
+    0.1
+}
+
+fn default_sun_irradiance() -> f64 {
+    1.0
+}
+
+// Below the horizon, the sky fades to black over this elevation range (degrees).
+const TWILIGHT_ELEVATION: f64 = 6.0;
+
+impl PhysicalSkyDescription {
+    pub fn sky(&self, t_real: f64) -> Sky {
+        let elevation = self.sun_elevation.at(t_real);
+        let azimuth = self.sun_azimuth.at(t_real).to_radians();
+        let elevation_above_horizon = elevation.max(0.0).to_radians();
+        let direction = Vector3d {
+            x: elevation_above_horizon.cos() * azimuth.cos(),
+            y: elevation_above_horizon.sin(),
+            z: elevation_above_horizon.cos() * azimuth.sin(),
+        };
+        let twilight_factor = (1.0 + elevation / TWILIGHT_ELEVATION).clamp(0.0, 1.0);
+        let physical_sky = PhysicalSky::new(
+            &direction, self.turbidity.at(t_real), self.intensity * twilight_factor);
+        let sun = if elevation > 0.0 {
+            Some(Sun {
+                direction,
+                color: physical_sky.sun_transmittance() * self.sun_irradiance,
+                angular_radius: self.sun_angular_radius,
+            })
+        } else {
+            None
+        };
+        Sky { background: Background::Physical(physical_sky), sun }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sun_sets() {
+        let description: PhysicalSkyDescription = toml::from_str(
+            "sun_elevation = { start = 30.0, end = -10.0 }").unwrap();
+        let noon = description.sky(0.0).sun.unwrap();
+        assert!((noon.direction.y - 0.5).abs() < 1e-9);
+        assert!(description.sky(0.5).sun.unwrap().color.g < noon.color.g);
+        assert!(description.sky(1.0).sun.is_none());
+    }
+}
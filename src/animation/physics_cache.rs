@@ -0,0 +1,167 @@
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::animation::timeline::Timeline;
+use crate::raytracer::quaternion::Quaternion;
+use crate::raytracer::sphere::Sphere;
+use crate::raytracer::vector3d::Vector3d;
+use crate::raytracer::world::World;
+
+const MAGIC: &[u8; 8] = b"BSPCACHE";
+// Magic, sphere count, frames per second, number of frames and fingerprint of the timeline
+const HEADER_SIZE: u64 = 40;
+// center, center_old, extra_brightness, orientation and orientation_old
+const VALUES_PER_SPHERE: usize = 15;
+const SPHERE_SIZE: u64 = 8 * VALUES_PER_SPHERE as u64;
+
+/// Writes the per-frame states of all spheres, as far as they are needed for rendering.
+pub struct PhysicsCacheWriter {
+    writer: BufWriter<File>,
+    sphere_count: usize,
+}
+
+impl PhysicsCacheWriter {
+    /// Describes the timeline in the header, so renders can check that it did not change.
+    pub fn create(path: &Path, sphere_count: usize, timeline: &Timeline) -> PhysicsCacheWriter {
+        let file = File::create(path)
+            .unwrap_or_else(|_| panic!("Unable to create physics cache {}", path.display()));
+        let mut writer = BufWriter::new(file);
+        writer.write_all(MAGIC)
+            .and_then(|_| writer.write_all(&(sphere_count as u64).to_le_bytes()))
+            .and_then(|_| writer.write_all(&timeline.fps.to_le_bytes()))
+            .and_then(|_| writer.write_all(&(timeline.num_frames() as u64).to_le_bytes()))
+            .and_then(|_| writer.write_all(&timeline.fingerprint().to_le_bytes()))
+            .unwrap_or_else(|e| panic!("Unable to write physics cache {}: {}", path.display(), e));
+        PhysicsCacheWriter { writer, sphere_count }
+    }
+
+    pub fn write_frame(&mut self, world: &World) {
+        assert_eq!(world.spheres().len(), self.sphere_count, "Number of spheres changed");
+        let values = world.spheres().iter().flat_map(|sphere| {
+            let (c, o) = (sphere.center, sphere.center_old);
+            let (q, p) = (sphere.orientation, sphere.orientation_old);
+            vec![c.x, c.y, c.z, o.x, o.y, o.z, sphere.extra_brightness,
+                 q.w, q.x, q.y, q.z, p.w, p.x, p.y, p.z]
+        }).flat_map(|value| value.to_le_bytes().to_vec()).collect::<Vec<u8>>();
+        self.writer.write_all(&values)
+            .and_then(|_| self.writer.flush())
+            .expect("Unable to write physics cache");
+    }
+}
+
+/// Random access to the frames written by `PhysicsCacheWriter`
+pub struct PhysicsCache {
+    file: File,
+    sphere_count: usize,
+    fps: f64,
+    num_frames: usize,
+    timeline_fingerprint: u64,
+    // Less than `num_frames` if the simulation was interrupted
+    frame_count: usize,
+}
+
+impl PhysicsCache {
+    pub fn open(path: &Path) -> PhysicsCache {
+        let mut file = File::open(path)
+            .unwrap_or_else(|_| panic!("Unable to open physics cache {}", path.display()));
+        let mut header = [0; HEADER_SIZE as usize];
+        file.read_exact(&mut header)
+            .unwrap_or_else(|e| panic!("Unable to read physics cache {}: {}", path.display(), e));
+        if &header[0..8] != MAGIC {
+            panic!("Not a physics cache: {}", path.display());
+        }
+        let sphere_count = u64::from_le_bytes(header[8..16].try_into().unwrap()) as usize;
+        let fps = f64::from_le_bytes(header[16..24].try_into().unwrap());
+        let num_frames = u64::from_le_bytes(header[24..32].try_into().unwrap()) as usize;
+        let timeline_fingerprint = u64::from_le_bytes(header[32..40].try_into().unwrap());
+        let file_size = file.metadata()
+            .unwrap_or_else(|e| panic!("Unable to read physics cache {}: {}", path.display(), e))
+            .len();
+        // An interrupted simulation leaves a partial last frame, which is ignored.
+        let frame_size = (sphere_count as u64 * SPHERE_SIZE).max(1);
+        let frame_count = ((file_size - HEADER_SIZE) / frame_size) as usize;
+        PhysicsCache { file, sphere_count, fps, num_frames, timeline_fingerprint, frame_count }
+    }
+
+    pub fn sphere_count(&self) -> usize {
+        self.sphere_count
+    }
+
+    pub fn fps(&self) -> f64 {
+        self.fps
+    }
+
+    /// Number of frames of the timeline the cache was simulated with
+    pub fn num_frames(&self) -> usize {
+        self.num_frames
+    }
+
+    /// `Timeline::fingerprint` of the timeline the cache was simulated with
+    pub fn timeline_fingerprint(&self) -> u64 {
+        self.timeline_fingerprint
+    }
+
+    /// Number of frames actually contained in the cache
+    pub fn frame_count(&self) -> usize {
+        self.frame_count
+    }
+
+    /// `spheres` provides the properties not changed by the simulation, e.g., radius and material.
+    pub fn frame(&mut self, frame_num: usize, spheres: &[Sphere]) -> World {
+        assert_eq!(spheres.len(), self.sphere_count, "Physics cache does not match the scene");
+        assert!(frame_num < self.frame_count,
+                "Frame {} is not in the physics cache ({} frames)", frame_num, self.frame_count);
+        let mut bytes = vec![0; self.sphere_count * SPHERE_SIZE as usize];
+        self.file.seek(SeekFrom::Start(
+            HEADER_SIZE + frame_num as u64 * self.sphere_count as u64 * SPHERE_SIZE))
+            .and_then(|_| self.file.read_exact(&mut bytes))
+            .expect("Unable to read physics cache");
+        let values = bytes.chunks(8)
+            .map(|value| f64::from_le_bytes(value.try_into().unwrap()))
+            .collect::<Vec<f64>>();
+        World::new(spheres.iter().zip(values.chunks(VALUES_PER_SPHERE)).map(|(sphere, v)| {
+            Sphere {
+                center: Vector3d { x: v[0], y: v[1], z: v[2] },
+                center_old: Vector3d { x: v[3], y: v[4], z: v[5] },
+                extra_brightness: v[6],
+                orientation: Quaternion { w: v[7], x: v[8], y: v[9], z: v[10] },
+                orientation_old: Quaternion { w: v[11], x: v[12], y: v[13], z: v[14] },
+                ..sphere.clone()
+            }
+        }).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::animation::animation::{make_world, physics_settings};
+
+    use super::*;
+
+    #[test]
+    fn test_read_frames_out_of_order() {
+        let path = std::env::temp_dir()
+            .join(format!("bouncing_spheres_test_read_frames_{}.cache", std::process::id()));
+        let initial_world = make_world();
+        let timeline = Timeline { duration: 0.2, fps: 20.0, time_remap: vec![] };
+        let mut writer = PhysicsCacheWriter::create(&path, initial_world.spheres().len(), &timeline);
+        let worlds = (0..3).scan(make_world(), |world, _| {
+            *world = world.advance(0.05, &physics_settings());
+            writer.write_frame(world);
+            Some(world.spheres().to_vec())
+        }).collect::<Vec<Vec<Sphere>>>();
+
+        let mut cache = PhysicsCache::open(&path);
+        assert_eq!((cache.fps(), cache.num_frames(), cache.frame_count()), (20.0, 4, 3));
+        assert_eq!(cache.timeline_fingerprint(), timeline.fingerprint());
+        for frame_num in [2, 0, 1].iter() {
+            let world = cache.frame(*frame_num, initial_world.spheres());
+            assert!(world.spheres().iter().zip(worlds[*frame_num].iter()).all(|(a, b)| {
+                a.center == b.center && a.center_old == b.center_old &&
+                    a.extra_brightness == b.extra_brightness && a.orientation == b.orientation
+            }));
+        }
+    }
+}
@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use rand::prelude::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
+
+use crate::animation::animation::physics_settings;
+use crate::animation::camera_track::CameraTrack;
+use crate::animation::environment::EnvironmentDescription;
+use crate::animation::physical_sky::PhysicalSkyDescription;
+use crate::animation::timeline::Timeline;
+use crate::raytracer::color::Color;
+use crate::raytracer::integrator::IntegratorKind;
+use crate::raytracer::light::Sun;
+use crate::raytracer::material::{default_refraction_index, Material};
+use crate::raytracer::physics::{GravityApproximation, PhysicsSettings};
+use crate::raytracer::quaternion::Quaternion;
+use crate::raytracer::sphere::Sphere;
+use crate::raytracer::texture::Texture;
+use crate::raytracer::vector3d::Vector3d;
+use crate::raytracer::world::World;
+
+/// Initial conditions of an animation, usually read from a TOML file.
+#[derive(Debug, Default, Deserialize)]
+pub struct Scene {
+    #[serde(default)]
+    pub spheres: Vec<SphereDescription>,
+    #[serde(default)]
+    pub random_spheres: Vec<RandomSpheres>,
+    // Uses the built-in camera movement if omitted.
+    pub camera: Option<CameraTrack>,
+    #[serde(default)]
+    pub timeline: Timeline,
+    pub sun: Option<Sun>,
+    // Uses the built-in sky gradient if omitted.
+    pub environment: Option<EnvironmentDescription>,
+    // Replaces `environment` and `sun` if given.
+    pub physical_sky: Option<PhysicalSkyDescription>,
+    #[serde(default)]
+    pub physics: PhysicsDescription,
+}
+
+/// Overrides of the built-in `physics_settings`
+#[derive(Debug, Default, Deserialize)]
+pub struct PhysicsDescription {
+    pub integrator: Option<IntegratorKind>,
+    // Split the time step of every frame to avoid tunnelling and energy blow-ups.
+    pub substeps: Option<usize>,
+    pub continuous_collision_detection: Option<bool>,
+    pub gravity_approximation: Option<GravityApproximation>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SphereDescription {
+    pub center: Vector3d,
+    pub radius: f64,
+    // Defaults to `radius^3`, i.e., the same density for all spheres.
+    pub mass: Option<f64>,
+    #[serde(default)]
+    pub speed: Vector3d,
+    // Radians per unit of time
+    #[serde(default)]
+    pub angular_velocity: Vector3d,
+    pub material: Material,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct ValueRange {
+    pub min: f64,
+    pub max: f64,
+}
+
+/// Procedurally generated spheres with uniformly distributed properties.
+#[derive(Debug, Deserialize)]
+pub struct RandomSpheres {
+    pub seed: u64,
+    pub count: usize,
+    pub x: ValueRange,
+    pub y: ValueRange,
+    pub z: ValueRange,
+    pub radius: ValueRange,
+    #[serde(default = "full_reflectiveness")]
+    pub reflectiveness: ValueRange,
+    #[serde(default = "no_reflection_fuzz")]
+    pub reflection_fuzz: ValueRange,
+    // Fraction of glass marbles among the spheres
+    #[serde(default)]
+    pub glass_probability: f64,
+    #[serde(default = "default_refraction_index")]
+    pub refraction_index: f64,
+    // Tints the glass marbles in their (random) albedo.
+    #[serde(default)]
+    pub glass_absorption: f64,
+    // Fraction of spheres glowing in their albedo
+    #[serde(default)]
+    pub emissive_probability: f64,
+    #[serde(default)]
+    pub emission_strength: f64,
+    #[serde(default)]
+    pub flash_emission: f64,
+}
+
+fn full_reflectiveness() -> ValueRange {
+    ValueRange { min: 1.0, max: 1.0 }
+}
+
+fn no_reflection_fuzz() -> ValueRange {
+    ValueRange { min: 0.0, max: 0.0 }
+}
+
+impl ValueRange {
+    fn sample(&self, rng: &mut StdRng) -> f64 {
+        if self.min < self.max {
+            rng.gen_range(self.min, self.max)
+        } else {
+            self.min
+        }
+    }
+}
+
+impl SphereDescription {
+    fn to_sphere(&self) -> Sphere {
+        Sphere {
+            center: self.center,
+            radius: self.radius,
+            material: self.material.clone(),
+            speed: self.speed,
+            mass: self.mass.unwrap_or_else(|| self.radius.powf(3.0)),
+            extra_brightness: 0.0,
+            center_old: self.center,
+            angular_velocity: self.angular_velocity,
+            orientation: Quaternion::identity(),
+            orientation_old: Quaternion::identity(),
+        }
+    }
+}
+
+impl RandomSpheres {
+    fn random_sphere(&self, rng: &mut StdRng) -> Sphere {
+        let radius = self.radius.sample(rng);
+        let center = Vector3d {
+            x: self.x.sample(rng),
+            y: self.y.sample(rng),
+            z: self.z.sample(rng),
+        };
+        let albedo = Color {
+            r: rng.gen_range(0.0, 1.0),
+            g: rng.gen_range(0.0, 1.0),
+            b: rng.gen_range(0.0, 1.0),
+        };
+        let reflectiveness = self.reflectiveness.sample(rng);
+        let reflection_fuzz = self.reflection_fuzz.sample(rng);
+        let is_glass = self.glass_probability > 0.0 &&
+            rng.gen::<f64>() < self.glass_probability;
+        let is_emissive = self.emissive_probability > 0.0 &&
+            rng.gen::<f64>() < self.emissive_probability;
+        Sphere {
+            center,
+            radius,
+            material: Material {
+                albedo: Texture::Solid(albedo),
+                reflectiveness,
+                reflection_fuzz,
+                transparency: if is_glass { 1.0 } else { 0.0 },
+                refraction_index: self.refraction_index,
+                absorption: (Color::white() + &(albedo * -1.0)) * self.glass_absorption,
+                emission: if is_emissive {
+                    albedo * self.emission_strength
+                } else {
+                    Color::black()
+                },
+                flash_emission: self.flash_emission,
+            },
+            speed: Vector3d::null(),
+            mass: radius.powf(3.0),
+            extra_brightness: 0.0,
+            center_old: center,
+            angular_velocity: Vector3d::null(),
+            orientation: Quaternion::identity(),
+            orientation_old: Quaternion::identity(),
+        }
+    }
+
+    fn spheres(&self) -> Vec<Sphere> {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(self.seed);
+        (0..self.count).map(|_| self.random_sphere(&mut rng)).collect()
+    }
+}
+
+impl Scene {
+    /// The paths of image textures and environment maps are relative to the scene file.
+    pub fn load(path: &str) -> Scene {
+        let mut scene_file_content = String::new();
+        File::open(path).and_then(|mut f| {
+            f.read_to_string(&mut scene_file_content)
+        }).unwrap_or_else(|_| panic!("Unable to read scene file: {}", path));
+        let mut scene = toml::from_str::<Scene>(&scene_file_content)
+            .unwrap_or_else(|e| panic!("Unable to parse scene file {}: {}", path, e));
+        if scene.camera.as_ref().is_some_and(|camera| camera.keyframes.is_empty()) {
+            panic!("Camera of scene file {} has no keyframes", path);
+        }
+        // The interpolation divides by the time between neighbouring keyframes.
+        let keyframes = scene.camera.as_ref().map_or(&[][..], |camera| &camera.keyframes[..]);
+        if let Some(pair) = keyframes.windows(2).find(|k| k[0].time >= k[1].time) {
+            panic!("Camera keyframe times of scene file {} are not strictly increasing \
+                    ({} is followed by {})", path, pair[0].time, pair[1].time);
+        }
+        scene.timeline.validate()
+            .unwrap_or_else(|e| panic!("Invalid timeline in scene file {}: {}", path, e));
+        let base_dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+        let mut images = HashMap::new();
+        for (i, sphere) in scene.spheres.iter_mut().enumerate() {
+            sphere.material.albedo.load_image(base_dir, &mut images).unwrap_or_else(|e| {
+                panic!("Unable to load texture of sphere {} in scene file {}: {}", i, path, e)
+            });
+        }
+        if let Some(environment) = scene.environment.as_mut() {
+            for map in environment.maps.iter_mut() {
+                map.path = base_dir.join(&map.path);
+            }
+        }
+        scene
+    }
+
+    pub fn make_world(&self) -> World {
+        World::new(self.random_spheres.iter()
+            .flat_map(|random_spheres| random_spheres.spheres())
+            .chain(self.spheres.iter().map(|s| s.to_sphere()))
+            .collect())
+    }
+
+    pub fn physics_settings(&self) -> PhysicsSettings {
+        let defaults = physics_settings();
+        PhysicsSettings {
+            integrator: self.physics.integrator.unwrap_or(defaults.integrator),
+            substeps: self.physics.substeps.unwrap_or(defaults.substeps),
+            continuous_collision_detection: self.physics.continuous_collision_detection
+                .unwrap_or(defaults.continuous_collision_detection),
+            gravity_approximation: self.physics.gravity_approximation
+                .unwrap_or(defaults.gravity_approximation),
+            ..defaults
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::animation::environment::Environment;
+    use crate::raytracer::image::Image;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_scene() {
+        let scene = toml::from_str::<Scene>(r#"
+            [[spheres]]
+            center = { x = 0.0, y = -10.0, z = 0.0 }
+            radius = 10.0
+            material = { albedo = { r = 0.5, g = 0.5, b = 0.5 }, reflectiveness = 0.5, reflection_fuzz = 0.1 }
+
+            [[random_spheres]]
+            seed = 3
+            count = 5
+            x = { min = -1.0, max = 1.0 }
+            y = { min = 2.0, max = 3.0 }
+            z = { min = -1.0, max = 1.0 }
+            radius = { min = 0.5, max = 0.5 }
+        "#).unwrap();
+        let world = scene.make_world();
+        assert_eq!(world.spheres().len(), 6);
+        assert_eq!(world.spheres()[5].mass, 1000.0);
+        assert!(world.spheres()[..5].iter().all(|s| s.radius == 0.5 && s.center.y >= 2.0));
+    }
+
+    #[test]
+    fn test_gravity_approximation() {
+        let scene = toml::from_str::<Scene>(r#"
+            [[random_spheres]]
+            seed = 4
+            count = 30
+            x = { min = -5.0, max = 5.0 }
+            y = { min = -5.0, max = 5.0 }
+            z = { min = -5.0, max = 5.0 }
+            radius = { min = 0.1, max = 0.3 }
+
+            [physics]
+            gravity_approximation = { barnes_hut = { theta = 0.5 } }
+        "#).unwrap();
+        let physics = scene.physics_settings();
+        assert_eq!(physics.gravity_approximation,
+                   GravityApproximation::BarnesHut { opening_angle: 0.5 });
+
+        let world = scene.make_world();
+        let approximated = world.advance(0.1, &physics);
+        let exact = world.advance(0.1, &PhysicsSettings {
+            gravity_approximation: GravityApproximation::Exact,
+            ..scene.physics_settings()
+        });
+        // All spheres start at rest, so only their speeds change in the first step.
+        let max_difference = |a: &World, b: &World| a.spheres().iter().zip(b.spheres().iter())
+            .map(|(a, b)| (a.speed - &b.speed).length())
+            .fold(0.0, f64::max);
+        let error = max_difference(&approximated, &exact);
+        assert!(error > 0.0 && error < 0.05 * max_difference(&exact, &world));
+    }
+
+    #[test]
+    #[should_panic(expected = "has no keyframes")]
+    fn test_reject_camera_without_keyframes() {
+        let scene_path = std::env::temp_dir()
+            .join(format!("bouncing_spheres_test_no_keyframes_{}.toml", std::process::id()));
+        std::fs::write(&scene_path, r#"
+            [camera]
+            interpolation = "linear"
+            keyframes = []
+        "#).unwrap();
+        Scene::load(scene_path.to_str().unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "are not strictly increasing (0.5 is followed by 0.2)")]
+    fn test_reject_unsorted_camera_keyframes() {
+        let scene_path = std::env::temp_dir()
+            .join(format!("bouncing_spheres_test_unsorted_keyframes_{}.toml", std::process::id()));
+        let keyframe = |time| format!(r#"
+            [[camera.keyframes]]
+            time = {}
+            position = {{ x = 0.0, y = 0.0, z = -10.0 }}
+            looks_at = {{ x = 0.0, y = 0.0, z = 0.0 }}
+            vertical_field_of_view = 45.0
+        "#, time);
+        let content = format!("[camera]\ninterpolation = \"linear\"\n{}{}{}",
+                              keyframe("0.0"), keyframe("0.5"), keyframe("0.2"));
+        std::fs::write(&scene_path, content).unwrap();
+        Scene::load(scene_path.to_str().unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "not strictly increasing in t_real")]
+    fn test_reject_unsorted_time_remap() {
+        let scene_path = std::env::temp_dir()
+            .join(format!("bouncing_spheres_test_time_remap_{}.toml", std::process::id()));
+        std::fs::write(&scene_path, r#"
+            [[timeline.time_remap]]
+            t_real = 0.5
+            t_world = 1.0
+
+            [[timeline.time_remap]]
+            t_real = 0.5
+            t_world = 2.0
+        "#).unwrap();
+        Scene::load(scene_path.to_str().unwrap());
+    }
+
+    #[test]
+    fn test_image_texture_relative_to_scene_file() {
+        let dir_path = std::env::temp_dir()
+            .join(format!("bouncing_spheres_test_scene_{}", std::process::id()));
+        std::fs::create_dir_all(&dir_path).unwrap();
+        Image { data: vec![Color::white(); 4], width: 2, height: 2 }
+            .save_png(&dir_path.join("texture.png"));
+        let scene_path = dir_path.join("scene.toml");
+        std::fs::write(&scene_path, r#"
+            [[spheres]]
+            center = { x = 0.0, y = 0.0, z = 0.0 }
+            radius = 1.0
+            material = { albedo = { image = "texture.png" }, reflectiveness = 0.0, reflection_fuzz = 0.0 }
+        "#).unwrap();
+        let world = Scene::load(scene_path.to_str().unwrap()).make_world();
+        assert_eq!(world.spheres()[0].material.albedo.average(), Color::white());
+    }
+
+    #[test]
+    fn test_environment_map_relative_to_scene_file() {
+        let dir_path = std::env::temp_dir()
+            .join(format!("bouncing_spheres_test_environment_{}", std::process::id()));
+        std::fs::create_dir_all(&dir_path).unwrap();
+        Image { data: vec![Color::white(); 8], width: 4, height: 2 }
+            .save_pfm(&dir_path.join("sky.pfm"));
+        let scene_path = dir_path.join("scene.toml");
+        std::fs::write(&scene_path, r#"
+            [[environment.maps]]
+            path = "sky.pfm"
+        "#).unwrap();
+        let scene = Scene::load(scene_path.to_str().unwrap());
+        let environment = scene.environment.unwrap();
+        assert_eq!(environment.maps[0].path, dir_path.join("sky.pfm"));
+        Environment::load(&environment);
+    }
+
+    #[test]
+    fn test_example_scene() {
+        let world = Scene::load("scenes/example.toml").make_world();
+        assert_eq!(world.spheres().len(), 82);
+    }
+}
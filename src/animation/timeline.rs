@@ -0,0 +1,166 @@
+use crate::animation::animation::world_time_from_real_time;
+
+/// Maps real time (fraction of the animation) to simulated world time.
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct TimeKeyframe {
+    pub t_real: f64,
+    pub t_world: f64,
+}
+
+/// Constant or changing linearly from `start` to `end` over the animation
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(untagged)]
+pub enum Animated {
+    Constant(f64),
+    Linear { start: f64, end: f64 },
+}
+
+impl Animated {
+    pub fn at(&self, t_real: f64) -> f64 {
+        match self {
+            Animated::Constant(value) => *value,
+            Animated::Linear { start, end } => start + (end - start) * t_real,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Timeline {
+    // Length of the resulting video in seconds
+    #[serde(default = "default_duration")]
+    pub duration: f64,
+    #[serde(default = "default_fps")]
+    pub fps: f64,
+    // Piecewise linear, sorted by `t_real`.
+    // Uses the built-in slow-motion curve if empty.
+    #[serde(default)]
+    pub time_remap: Vec<TimeKeyframe>,
+}
+
+fn default_duration() -> f64 {
+    38.4
+}
+
+fn default_fps() -> f64 {
+    25.0
+}
+
+impl Default for Timeline {
+    fn default() -> Timeline {
+        Timeline {
+            duration: default_duration(),
+            fps: default_fps(),
+            time_remap: vec![],
+        }
+    }
+}
+
+impl Timeline {
+    pub fn num_frames(&self) -> usize {
+        (self.duration * self.fps).round() as usize
+    }
+
+    pub fn t_real(&self, frame_num: usize) -> f64 {
+        frame_num as f64 / self.num_frames() as f64
+    }
+
+    pub fn t_real_previous_frame(&self, frame_num: usize) -> f64 {
+        ((frame_num as f64 - 1.0) / self.num_frames() as f64).max(0.0)
+    }
+
+    pub fn world_time(&self, t_real: f64) -> f64 {
+        let keys = &self.time_remap;
+        match (keys.first(), keys.last()) {
+            (Some(first), Some(last)) => {
+                if t_real <= first.t_real {
+                    first.t_world
+                } else if t_real >= last.t_real {
+                    last.t_world
+                } else {
+                    let i = keys.iter().rposition(|k| k.t_real <= t_real).unwrap();
+                    let (a, b) = (keys[i], keys[i + 1]);
+                    let u = (t_real - a.t_real) / (b.t_real - a.t_real);
+                    a.t_world + u * (b.t_world - a.t_world)
+                }
+            }
+            _ => world_time_from_real_time(t_real)
+        }
+    }
+
+    /// Checks that there is at least one frame and that the remap keys are sorted.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.num_frames() == 0 {
+            return Err(format!("{} s at {} fps round to 0 frames", self.duration, self.fps));
+        }
+        // `world_time` interpolates between neighbours, equal keys would divide by zero.
+        if let Some(pair) = self.time_remap.windows(2).find(|k| k[0].t_real >= k[1].t_real) {
+            return Err(format!("time_remap keys are not strictly increasing in t_real \
+                                ({} is followed by {})", pair[0].t_real, pair[1].t_real));
+        }
+        Ok(())
+    }
+
+    /// Hash of everything that determines the world times of the frames
+    pub fn fingerprint(&self) -> u64 {
+        // FNV-1a, which unlike `DefaultHasher` is stable across Rust versions.
+        let values = vec![self.duration, self.fps].into_iter()
+            .chain(self.time_remap.iter().flat_map(|k| vec![k.t_real, k.t_world]));
+        values.flat_map(|value| value.to_le_bytes().to_vec())
+            .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+            })
+    }
+
+    /// Simulated time passing between the previous and the given frame.
+    pub fn delta_t(&self, frame_num: usize) -> f64 {
+        self.world_time(self.t_real(frame_num)) -
+            self.world_time(self.t_real_previous_frame(frame_num))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_remap() {
+        let timeline = Timeline {
+            duration: 2.0,
+            fps: 5.0,
+            time_remap: vec![
+                TimeKeyframe { t_real: 0.0, t_world: 0.0 },
+                TimeKeyframe { t_real: 0.5, t_world: 0.1 },
+                TimeKeyframe { t_real: 1.0, t_world: 1.1 },
+            ],
+        };
+        assert_eq!(timeline.num_frames(), 10);
+        assert_eq!(timeline.delta_t(0), 0.0);
+        assert!((timeline.delta_t(2) - 0.02).abs() < 1e-12);
+        assert!((timeline.delta_t(8) - 0.2).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_fingerprint_includes_time_remap() {
+        let key = |t_real, t_world| TimeKeyframe { t_real, t_world };
+        let timeline = Timeline { time_remap: vec![key(0.0, 0.0), key(1.0, 1.0)],
+                                  ..Timeline::default() };
+        let remapped = Timeline { time_remap: vec![key(0.0, 0.0), key(1.0, 2.0)],
+                                  ..timeline.clone() };
+        assert_eq!(timeline.fingerprint(), timeline.clone().fingerprint());
+        assert_ne!(timeline.fingerprint(), remapped.fingerprint());
+        assert_ne!(timeline.fingerprint(), Timeline::default().fingerprint());
+    }
+
+    #[test]
+    fn test_validate() {
+        let key = |t_real| TimeKeyframe { t_real, t_world: 0.0 };
+        let timeline = Timeline { time_remap: vec![key(0.0), key(1.0)], ..Timeline::default() };
+        assert!(timeline.validate().is_ok());
+        let no_frames = Timeline { duration: 0.01, fps: 25.0, time_remap: vec![] };
+        assert!(no_frames.validate().is_err());
+        let duplicate = Timeline { time_remap: vec![key(0.0), key(0.5), key(0.5)], ..timeline };
+        assert!(duplicate.validate().is_err());
+        let unsorted = Timeline { time_remap: vec![key(0.5), key(0.0)], ..duplicate };
+        assert!(unsorted.validate().is_err());
+    }
+}
@@ -0,0 +1,175 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use color_quant::NeuQuant;
+use gif::{Encoder, Frame, Repeat};
+use log::info;
+
+/// Keeps GIF previews small, usually read from the `[video.gif]` table of the config file.
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct GifSettings {
+    // Only use every n-th frame.
+    pub frame_step: usize,
+    // Shrink the frames by this factor, averaging the pixels.
+    pub downscale: usize,
+    // Floyd-Steinberg error diffusion, to avoid banding with only 256 colors per frame
+    pub dither: bool,
+}
+
+impl Default for GifSettings {
+    fn default() -> Self {
+        GifSettings { frame_step: 1, downscale: 1, dither: true }
+    }
+}
+
+/// Animated GIF written frame by frame, each one with its own palette
+pub struct GifWriter {
+    path: PathBuf,
+    settings: GifSettings,
+    // In centiseconds
+    delay: u16,
+    // Created with the first frame, because its resolution is needed.
+    encoder: Option<Encoder<BufWriter<File>>>,
+}
+
+impl GifWriter {
+    pub fn create(path: &Path, settings: GifSettings, fps: f64) -> GifWriter {
+        info!("Saving {}", path.display());
+        let frame_step = settings.frame_step.max(1);
+        GifWriter {
+            path: path.to_path_buf(),
+            settings: GifSettings { frame_step, downscale: settings.downscale.max(1), ..settings },
+            delay: (100.0 * frame_step as f64 / fps).round().max(1.0) as u16,
+            encoder: None,
+        }
+    }
+
+    /// Takes 8-bit RGB triples, starting with the top row.
+    pub fn write_frame(&mut self, rgb: &[u8], width: usize, height: usize, frame_num: usize) {
+        if !frame_num.is_multiple_of(self.settings.frame_step) {
+            return;
+        }
+        let (rgb, width, height) = downscale(rgb, width, height, self.settings.downscale);
+        let (palette, indices) = quantize(&rgb, width, self.settings.dither);
+        let path = &self.path;
+        let encoder = self.encoder.get_or_insert_with(|| {
+            let file = File::create(path)
+                .unwrap_or_else(|_| panic!("Unable to create file {}", path.display()));
+            let mut encoder = Encoder::new(BufWriter::new(file), width as u16, height as u16, &[])
+                .unwrap_or_else(|e| panic!("Unable to write file {}: {}", path.display(), e));
+            encoder.set_repeat(Repeat::Infinite)
+                .unwrap_or_else(|e| panic!("Unable to write file {}: {}", path.display(), e));
+            encoder
+        });
+        let frame = Frame {
+            delay: self.delay,
+            width: width as u16,
+            height: height as u16,
+            palette: Some(palette),
+            buffer: indices.into(),
+            ..Frame::default()
+        };
+        encoder.write_frame(&frame)
+            .unwrap_or_else(|e| panic!("Unable to write file {}: {}", path.display(), e));
+    }
+
+    pub fn finish(&mut self) {
+        if let Some(encoder) = self.encoder.take() {
+            encoder.into_inner()
+                .unwrap_or_else(|e| panic!("Unable to write file {}: {}", self.path.display(), e));
+        }
+    }
+}
+
+fn downscale(rgb: &[u8], width: usize, height: usize, factor: usize) -> (Vec<u8>, usize, usize) {
+    let (new_width, new_height) = ((width / factor).max(1), (height / factor).max(1));
+    let data = (0..new_height).flat_map(|y| (0..new_width).map(move |x| (x, y)))
+        .flat_map(|(x, y)| {
+            let pixels = (y * factor..((y + 1) * factor).min(height))
+                .flat_map(|sy| (x * factor..((x + 1) * factor).min(width)).map(move |sx| (sx, sy)))
+                .collect::<Vec<(usize, usize)>>();
+            (0..3).map(move |c| {
+                let sum: usize = pixels.iter()
+                    .map(|(sx, sy)| rgb[3 * (sy * width + sx) + c] as usize)
+                    .sum();
+                (sum / pixels.len()) as u8
+            })
+        }).collect();
+    (data, new_width, new_height)
+}
+
+/// Reduces the colors to a palette of (at most) 256 entries and returns the palette indices.
+fn quantize(rgb: &[u8], width: usize, dither: bool) -> (Vec<u8>, Vec<u8>) {
+    let rgba = rgb.chunks(3).flat_map(|c| vec![c[0], c[1], c[2], 255]).collect::<Vec<u8>>();
+    let quantizer = NeuQuant::new(10, 256, &rgba);
+    let palette = quantizer.color_map_rgb();
+    let index_of = |c: &[f64; 3]| {
+        let pixel = [c[0].round() as u8, c[1].round() as u8, c[2].round() as u8, 255];
+        quantizer.index_of(&pixel)
+    };
+    if !dither {
+        let indices = rgb.chunks(3)
+            .map(|c| index_of(&[c[0] as f64, c[1] as f64, c[2] as f64]) as u8)
+            .collect();
+        return (palette, indices);
+    }
+    let height = rgb.len() / (3 * width);
+    let mut colors = rgb.chunks(3)
+        .map(|c| [c[0] as f64, c[1] as f64, c[2] as f64])
+        .collect::<Vec<[f64; 3]>>();
+    let mut indices = vec![0; colors.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let i = y * width + x;
+            let color = colors[i].iter().map(|c| c.clamp(0.0, 255.0)).collect::<Vec<f64>>();
+            let index = index_of(&[color[0], color[1], color[2]]);
+            indices[i] = index as u8;
+            let error = (0..3).map(|c| color[c] - palette[3 * index + c] as f64)
+                .collect::<Vec<f64>>();
+            let mut diffuse = |dx: isize, dy: usize, weight: f64| {
+                let (nx, ny) = (x as isize + dx, y + dy);
+                if nx >= 0 && (nx as usize) < width && ny < height {
+                    let neighbor = &mut colors[ny * width + nx as usize];
+                    (0..3).for_each(|c| neighbor[c] += error[c] * weight);
+                }
+            };
+            diffuse(1, 0, 7.0 / 16.0);
+            diffuse(-1, 1, 3.0 / 16.0);
+            diffuse(0, 1, 5.0 / 16.0);
+            diffuse(1, 1, 1.0 / 16.0);
+        }
+    }
+    (palette, indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dithering_preserves_average_color() {
+        // A smooth gradient with more colors than fit into a palette
+        let (width, height) = (64, 64);
+        let rgb = (0..height).flat_map(|y| (0..width).flat_map(move |x| {
+            vec![(4 * x) as u8, (4 * y) as u8, (2 * (x + y)) as u8]
+        })).collect::<Vec<u8>>();
+        let average = |colors: &[u8]| {
+            (0..3).map(|c| {
+                let sum: f64 = colors.iter().skip(c).step_by(3).map(|&v| v as f64).sum();
+                sum / (width * height) as f64
+            }).collect::<Vec<f64>>()
+        };
+        let (palette, indices) = quantize(&rgb, width, true);
+        let dithered = indices.iter()
+            .flat_map(|&i| palette[3 * i as usize..3 * i as usize + 3].to_vec())
+            .collect::<Vec<u8>>();
+        let (expected, result) = (average(&rgb), average(&dithered));
+        assert!((0..3).all(|c| (expected[c] - result[c]).abs() < 1.0));
+
+        let (small, small_width, small_height) = downscale(&rgb, width, height, 3);
+        assert_eq!((small_width, small_height, small.len()), (21, 21, 21 * 21 * 3));
+        assert_eq!(&small[..3], &[4, 4, 4]);
+    }
+}
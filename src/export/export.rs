@@ -1,74 +1,250 @@
 use std::fs;
-use std::path::Path;
-use std::process::Command;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
 use chrono::{DateTime, Utc};
-use log::info;
+use log::{info, warn};
 
+use crate::export::animated_gif::GifWriter;
+use crate::export::video::{VideoFormat, VideoSettings, VideoStream};
 use crate::raytracer::image::Image;
+use crate::raytracer::render::Aovs;
+
+enum Stream {
+    Ffmpeg(VideoStream),
+    Gif(GifWriter),
+}
 
 pub struct Exporter {
-    dir_path_str: Option<String>
+    dir_path_str: Option<String>,
+    video: VideoSettings,
+    fps: f64,
+    // Also save the linear radiance of each frame.
+    hdr: bool,
+    // Opened with the first frame, because its resolution is needed.
+    stream: Option<Stream>,
 }
 
 impl Exporter {
-    pub fn new(enabled: bool) -> Exporter {
-        if enabled {
-            let datetime: DateTime<Utc> = SystemTime::now().into();
-            let dir_path_str = format!("./output/{}", datetime.format("%Y-%m-%d_%H-%M-%S"));
+    /// Writes into a new timestamped directory below `./output` if no `dir_path_str` is given.
+    pub fn new(enabled: bool, dir_path_str: Option<&str>, video: VideoSettings, fps: f64,
+               hdr: bool) -> Exporter {
+        let dir_path_str = if enabled || dir_path_str.is_some() {
+            let dir_path_str = match dir_path_str {
+                Some(dir_path_str) => dir_path_str.trim_end_matches('/').to_string(),
+                None => {
+                    let datetime: DateTime<Utc> = SystemTime::now().into();
+                    format!("./output/{}", datetime.format("%Y-%m-%d_%H-%M-%S"))
+                }
+            };
             fs::create_dir_all(Path::new(&dir_path_str))
-                .expect(&format!("Can not create output directory: {}", dir_path_str));
-            Exporter { dir_path_str: Some(dir_path_str) }
+                .unwrap_or_else(|_| panic!("Can not create output directory: {}", dir_path_str));
+            Some(dir_path_str)
         } else {
-            Exporter { dir_path_str: None }
+            None
+        };
+        Exporter { dir_path_str, video, fps, hdr, stream: None }
+    }
+
+    fn frame_path(dir_path_str: &str, frame_num: usize) -> PathBuf {
+        Path::new(dir_path_str).join(format!("{:08}.png", frame_num))
+    }
+
+    fn video_path(&self, dir_path_str: &str) -> PathBuf {
+        match self.video.format {
+            VideoFormat::Ffmpeg => PathBuf::from(format!("{}.{}", dir_path_str, self.video.container)),
+            VideoFormat::Gif => gif_path(dir_path_str),
+        }
+    }
+
+    fn open_stream(&self, dir_path_str: &str, width: usize, height: usize) -> Stream {
+        let video_path = self.video_path(dir_path_str);
+        match self.video.format {
+            VideoFormat::Ffmpeg =>
+                match VideoStream::open(&self.video, width, height, self.fps, &video_path) {
+                    Ok(stream) => Stream::Ffmpeg(stream),
+                    Err(e) => {
+                        self.check_missing_encoder(e);
+                        Stream::Gif(GifWriter::create(&gif_path(dir_path_str), self.video.gif,
+                                                      self.fps))
+                    }
+                },
+            VideoFormat::Gif => Stream::Gif(GifWriter::create(&video_path, self.video.gif, self.fps)),
+        }
+    }
+
+    // Falls back to an animated GIF if the encoder is not installed.
+    fn check_missing_encoder(&self, error: io::Error) {
+        if error.kind() != io::ErrorKind::NotFound {
+            panic!("Unable to execute {}: {}", self.video.encoder, error);
+        }
+        warn!("{} not found, writing an animated GIF instead", self.video.encoder);
+    }
+
+    fn write_gif(&self, dir_path_str: &str, frames: Range<usize>) {
+        let mut gif = GifWriter::create(&gif_path(dir_path_str), self.video.gif, self.fps);
+        for frame_num in frames {
+            let (rgb, width, height) = read_png(&Exporter::frame_path(dir_path_str, frame_num));
+            gif.write_frame(&rgb, width, height, frame_num);
         }
+        gif.finish();
     }
 
-    pub fn process_frame(&self, frame: &Image, frame_num: usize) {
+    /// Whether anything is saved, i.e., extra passes only for export are worth rendering
+    pub fn is_enabled(&self) -> bool {
+        self.dir_path_str.is_some()
+    }
+
+    /// Whether the frame has already been saved, e.g., by an earlier (interrupted) run
+    pub fn frame_exists(&self, frame_num: usize) -> bool {
         match &self.dir_path_str {
-            Some(dir_path_str) => {
-                let image_path = &Path::new(&dir_path_str)
-                    .join(format!("{:08}.png", frame_num));
+            Some(dir_path_str) if !self.video.stream =>
+                Exporter::frame_path(dir_path_str, frame_num).is_file(),
+            _ => false,
+        }
+    }
+
+    /// Takes the linear radiance returned by `render` and its tone-mapped version for display.
+    pub fn process_frame(&mut self, radiance: &Image, frame: &Image, frame_num: usize) {
+        if let Some(dir_path_str) = &self.dir_path_str {
+            if self.hdr {
+                let hdr_path = Path::new(dir_path_str).join(format!("{:08}.pfm", frame_num));
+                info!("Saving {}", hdr_path.display());
+                radiance.save_pfm(&hdr_path);
+            }
+            if self.video.stream {
+                if self.stream.is_none() {
+                    self.stream = Some(self.open_stream(dir_path_str, frame.width, frame.height));
+                }
+                match self.stream.as_mut() {
+                    Some(Stream::Ffmpeg(stream)) => stream.write_frame(frame),
+                    Some(Stream::Gif(gif)) =>
+                        gif.write_frame(&frame.to_rgb8(), frame.width, frame.height, frame_num),
+                    None => {}
+                }
+            } else {
+                let image_path = &Exporter::frame_path(dir_path_str, frame_num);
                 info!("Saving {}", image_path.display());
-                frame.save_png(image_path);
+                // Renamed when complete, so `frame_exists` never sees a truncated file
+                // of an interrupted run.
+                let temp_path = image_path.with_extension("png.tmp");
+                frame.save_png(&temp_path);
+                fs::rename(&temp_path, image_path)
+                    .unwrap_or_else(|_| panic!("Unable to save {}", image_path.display()));
             }
-            None => {}
         }
     }
 
-    pub fn combine_frames_to_video(&self) {
-        match &self.dir_path_str {
-            Some(dir_path_str) => {
-                let video_path = format!("{}.mp4", dir_path_str);
-                info!("Saving {}", video_path);
-                Command::new("ffmpeg")
-                    .arg("-i")
-                    .arg(format!("{}/%08d.png", dir_path_str))
-                    .arg("-c:v")
-                    .arg("libx264")
-                    .arg("-preset")
-                    .arg("veryslow")
-                    .arg("-profile:v")
-                    .arg("high")
-                    .arg("-crf")
-                    .arg("18")
-                    .arg("-coder")
-                    .arg("1")
-                    .arg("-pix_fmt")
-                    .arg("yuv420p")
-                    .arg("-movflags")
-                    .arg("+faststart")
-                    .arg("-g")
-                    .arg("60")
-                    .arg("-bf")
-                    .arg("2")
-                    .arg("-y")
-                    .arg(video_path)
-                    .output()
-                    .expect("failed to execute ffmpeg");
+    /// Saves each pass as a Portable Float Map into its own subdirectory, e.g., `depth`.
+    pub fn process_aovs(&self, aovs: &Aovs, frame_num: usize) {
+        if let Some(dir_path_str) = &self.dir_path_str {
+            let passes = [
+                ("depth", &aovs.depth),
+                ("normal", &aovs.normal),
+                ("albedo", &aovs.albedo),
+                ("sphere_index", &aovs.sphere_index),
+                ("motion", &aovs.motion),
+            ];
+            for (name, pass) in passes.iter() {
+                let pass_dir_path = Path::new(dir_path_str).join(name);
+                fs::create_dir_all(&pass_dir_path).unwrap_or_else(|_| {
+                    panic!("Can not create output directory: {}", pass_dir_path.display())
+                });
+                let pass_path = pass_dir_path.join(format!("{:08}.pfm", frame_num));
+                info!("Saving {}", pass_path.display());
+                pass.save_pfm(&pass_path);
+            }
+        }
+    }
+
+    /// Finishes the video stream, or combines the saved frames into a video.
+    /// The latter is only done if all of them are present, i.e., not after partial renders.
+    pub fn finish(&mut self, frames: Range<usize>) {
+        if let Some(stream) = &mut self.stream {
+            match stream {
+                Stream::Ffmpeg(stream) => stream.finish(),
+                Stream::Gif(gif) => gif.finish(),
+            }
+        } else if let Some(dir_path_str) = &self.dir_path_str {
+            if self.video.stream {
+                return;
+            }
+            if let Some(missing) = frames.clone().find(|&f| !self.frame_exists(f)) {
+                info!("Not combining frames to video, because frame {} is missing", missing);
+                return;
+            }
+            info!("Combining frames {} to {} to video", frames.start, frames.end - 1);
+            match self.video.format {
+                VideoFormat::Ffmpeg => {
+                    let video_path = self.video_path(dir_path_str);
+                    info!("Saving {}", video_path.display());
+                    let encoded = self.video.encode_png_frames(dir_path_str, self.fps,
+                                                               frames.start, &video_path);
+                    if let Err(e) = encoded {
+                        self.check_missing_encoder(e);
+                        self.write_gif(dir_path_str, frames);
+                    }
+                }
+                VideoFormat::Gif => self.write_gif(dir_path_str, frames),
             }
-            None => {}
         }
     }
 }
+
+fn gif_path(dir_path_str: &str) -> PathBuf {
+    PathBuf::from(format!("{}.gif", dir_path_str))
+}
+
+/// Returns the 8-bit RGB triples of a frame saved by `process_frame`.
+fn read_png(path: &Path) -> (Vec<u8>, usize, usize) {
+    let file = File::open(path)
+        .unwrap_or_else(|_| panic!("Unable to open frame {}", path.display()));
+    let (info, mut reader) = png::Decoder::new(file).read_info()
+        .unwrap_or_else(|e| panic!("Unable to read frame {}: {}", path.display(), e));
+    assert!(info.color_type == png::ColorType::RGB && info.bit_depth == png::BitDepth::Eight,
+            "Unexpected pixel format of frame {}", path.display());
+    let mut buffer = vec![0; info.buffer_size()];
+    reader.next_frame(&mut buffer)
+        .unwrap_or_else(|e| panic!("Unable to read frame {}: {}", path.display(), e));
+    (buffer, info.width as usize, info.height as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::raytracer::color::Color;
+
+    use super::*;
+
+    #[test]
+    fn test_frame_exists() {
+        let dir_path = std::env::temp_dir()
+            .join(format!("bouncing_spheres_test_frame_exists_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir_path);
+        let mut exporter = Exporter::new(false, dir_path.to_str(), VideoSettings::default(), 30.0, false);
+        let image = Image { data: vec![Color::black(); 4], width: 2, height: 2 };
+        exporter.process_frame(&image, &image, 1);
+        assert!(!exporter.frame_exists(0));
+        assert!(exporter.frame_exists(1));
+        assert!(!dir_path.join("00000001.png.tmp").exists());
+        assert!(!Exporter::new(false, None, VideoSettings::default(), 30.0, false).frame_exists(1));
+    }
+
+    #[test]
+    fn test_missing_encoder_falls_back_to_gif() {
+        let dir_path = std::env::temp_dir()
+            .join(format!("bouncing_spheres_test_missing_encoder_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir_path);
+        let video = VideoSettings {
+            encoder: "bouncing_spheres_missing_encoder".to_string(),
+            ..VideoSettings::default()
+        };
+        let mut exporter = Exporter::new(true, dir_path.to_str(), video, 30.0, false);
+        let image = Image { data: vec![Color::white(); 4], width: 2, height: 2 };
+        (0..3).for_each(|frame_num| exporter.process_frame(&image, &image, frame_num));
+        exporter.finish(0..3);
+        assert!(gif_path(dir_path.to_str().unwrap()).is_file());
+    }
+}
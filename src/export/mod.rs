@@ -1,2 +1,5 @@
+pub mod animated_gif;
+#[allow(clippy::module_inception)]
 pub mod export;
 pub mod stopwatch;
+pub mod video;
@@ -9,7 +9,7 @@ impl Stopwatch for Instant {
         let now = Instant::now();
         let elapsed = now.duration_since(*self);
         *self = now;
-        return elapsed;
+        elapsed
     }
 }
 
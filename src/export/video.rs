@@ -0,0 +1,211 @@
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
+
+use log::info;
+
+use crate::export::animated_gif::GifSettings;
+use crate::raytracer::image::Image;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum VideoFormat {
+    Ffmpeg,
+    // Written without any external encoder
+    Gif,
+}
+
+/// How frames are encoded into a video, usually read from the `[video]` table of the config file.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct VideoSettings {
+    pub format: VideoFormat,
+    // Pipe the frames into the encoder while rendering instead of saving them as PNG files.
+    pub stream: bool,
+    // Anything accepting the command-line arguments of ffmpeg
+    pub encoder: String,
+    pub codec: String,
+    pub crf: u32,
+    pub preset: String,
+    pub pix_fmt: String,
+    // File extension of the video, e.g., `mp4` or `mkv`
+    pub container: String,
+    pub gif: GifSettings,
+}
+
+impl Default for VideoSettings {
+    fn default() -> Self {
+        VideoSettings {
+            format: VideoFormat::Ffmpeg,
+            stream: false,
+            encoder: "ffmpeg".to_string(),
+            codec: "libx264".to_string(),
+            crf: 18,
+            preset: "veryslow".to_string(),
+            pix_fmt: "yuv420p".to_string(),
+            container: "mp4".to_string(),
+            gif: GifSettings::default(),
+        }
+    }
+}
+
+impl VideoSettings {
+    fn command(&self, input_args: &[String], video_path: &Path) -> Command {
+        let mut command = Command::new(&self.encoder);
+        command
+            .args(input_args)
+            .arg("-c:v")
+            .arg(&self.codec)
+            .arg("-preset")
+            .arg(&self.preset)
+            .arg("-crf")
+            .arg(self.crf.to_string())
+            .arg("-pix_fmt")
+            .arg(&self.pix_fmt);
+        if self.codec == "libx264" {
+            command.arg("-profile:v").arg("high").arg("-coder").arg("1");
+        }
+        if self.container == "mp4" || self.container == "mov" {
+            command.arg("-movflags").arg("+faststart");
+        }
+        command
+            .arg("-g")
+            .arg("60")
+            .arg("-bf")
+            .arg("2")
+            .arg("-y")
+            .arg(video_path);
+        command
+    }
+
+    /// Encodes the PNG files `first_frame_num`, `first_frame_num + 1`, ... of a directory.
+    /// Only fails if the encoder can not be executed, e.g., because it is not installed.
+    pub fn encode_png_frames(&self, dir_path_str: &str, fps: f64, first_frame_num: usize,
+                             video_path: &Path) -> io::Result<()> {
+        let status = self.command(&[
+            "-framerate".to_string(), fps.to_string(),
+            "-start_number".to_string(), first_frame_num.to_string(),
+            "-i".to_string(), format!("{}/%08d.png", dir_path_str),
+        ], video_path)
+            .status()?;
+        check_status(&self.encoder, status);
+        Ok(())
+    }
+}
+
+fn check_status(encoder: &str, status: ExitStatus) {
+    if !status.success() {
+        panic!("{} failed with {}", encoder, status);
+    }
+}
+
+/// Encoder process receiving raw RGB frames on its standard input
+pub struct VideoStream {
+    encoder: String,
+    width: usize,
+    height: usize,
+    process: Child,
+    stdin: Option<ChildStdin>,
+}
+
+impl VideoStream {
+    /// Only fails if the encoder can not be executed, e.g., because it is not installed.
+    pub fn open(settings: &VideoSettings, width: usize, height: usize, fps: f64,
+                video_path: &Path) -> io::Result<VideoStream> {
+        info!("Streaming frames into {}", video_path.display());
+        let mut process = settings.command(&[
+            "-f".to_string(), "rawvideo".to_string(),
+            "-pix_fmt".to_string(), "rgb24".to_string(),
+            "-s".to_string(), format!("{}x{}", width, height),
+            "-framerate".to_string(), fps.to_string(),
+            "-i".to_string(), "-".to_string(),
+        ], video_path)
+            .stdin(Stdio::piped())
+            .spawn()?;
+        let stdin = process.stdin.take();
+        Ok(VideoStream { encoder: settings.encoder.clone(), width, height, process, stdin })
+    }
+
+    pub fn write_frame(&mut self, frame: &Image) {
+        assert_eq!((frame.width, frame.height), (self.width, self.height),
+                   "All frames of a video need the same resolution.");
+        let written = self.stdin.as_mut()
+            .expect("Video stream already finished")
+            .write_all(&frame.to_rgb8());
+        if let Err(e) = written {
+            // Most likely the encoder died, so its exit status is more helpful.
+            self.stdin = None;
+            let status = self.process.wait()
+                .unwrap_or_else(|_| panic!("Unable to wait for {}", self.encoder));
+            check_status(&self.encoder, status);
+            panic!("Unable to write frame to {}: {}", self.encoder, e);
+        }
+    }
+
+    /// Closes the input of the encoder and waits for it to write the video.
+    pub fn finish(&mut self) {
+        self.stdin = None;
+        let status = self.process.wait()
+            .unwrap_or_else(|_| panic!("Unable to wait for {}", self.encoder));
+        check_status(&self.encoder, status);
+    }
+}
+
+impl Drop for VideoStream {
+    // Does not leave the encoder behind if rendering panics before `finish`.
+    fn drop(&mut self) {
+        self.stdin = None;
+        let _ = self.process.wait();
+    }
+}
+
+// The stub encoders are shell scripts.
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    use crate::raytracer::color::Color;
+
+    use super::*;
+
+    fn stub_encoder(name: &str, script: &str) -> VideoSettings {
+        let path = std::env::temp_dir().join(format!("{}_{}.sh", name, std::process::id()));
+        fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        VideoSettings { encoder: path.to_str().unwrap().to_string(), ..VideoSettings::default() }
+    }
+
+    fn stream_frames(settings: &VideoSettings, video_path: &Path) {
+        let frame = Image { data: vec![Color::white(); 6], width: 3, height: 2 };
+        let mut stream = VideoStream::open(settings, 3, 2, 30.0, video_path).unwrap();
+        for _ in 0..4 {
+            stream.write_frame(&frame);
+        }
+        stream.finish();
+    }
+
+    #[test]
+    fn test_stream_frames() {
+        // Writes its input into the last argument, i.e., the video path.
+        let settings = stub_encoder("bouncing_spheres_test_stream_frames",
+                                    "for last; do :; done; cat > \"$last\"");
+        let video_path: PathBuf = std::env::temp_dir()
+            .join(format!("bouncing_spheres_test_stream_frames_{}.mp4", std::process::id()));
+        stream_frames(&settings, &video_path);
+        let video = fs::read(&video_path).unwrap();
+        assert_eq!(video.len(), 4 * 3 * 2 * 3);
+        assert!(video.iter().all(|&c| c == 255));
+    }
+
+    #[test]
+    #[should_panic(expected = "failed with exit status: 3")]
+    fn test_report_encoder_failure() {
+        let settings = stub_encoder("bouncing_spheres_test_report_encoder_failure",
+                                    "cat > /dev/null; exit 3");
+        stream_frames(&settings, &std::env::temp_dir().join(
+            format!("bouncing_spheres_test_report_encoder_failure_{}.mp4", std::process::id())));
+    }
+}
@@ -0,0 +1,22 @@
+//! Raytracer, physics and export building blocks of the bouncing-spheres animation.
+//!
+//! The `main` binary is just one consumer of this crate.
+//! Other tools can build a `World`, advance it with `World::advance`
+//! and render it with `render::render` in the same way.
+
+#[macro_use]
+extern crate serde_derive;
+
+pub mod animation;
+pub mod export;
+pub mod raytracer;
+
+pub use crate::raytracer::camera::{Camera, CameraRange};
+pub use crate::raytracer::color::Color;
+pub use crate::raytracer::image::Image;
+pub use crate::raytracer::material::Material;
+pub use crate::raytracer::physics::PhysicsSettings;
+pub use crate::raytracer::render::{Aovs, render, render_aovs, Sky};
+pub use crate::raytracer::sphere::Sphere;
+pub use crate::raytracer::vector3d::Vector3d;
+pub use crate::raytracer::world::World;
@@ -6,22 +6,30 @@ extern crate serde_derive;
 extern crate simple_logger;
 
 use std::collections::HashMap;
+use std::fs;
 use std::fs::File;
 use std::io::Read;
-use std::time::Instant;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use log::info;
 use pixel_canvas::{Canvas, Image as CanvasImage};
 use structopt::StructOpt;
 
-use crate::animation::animation::{camera_range, make_world, num_frames, physics_settings, sky};
-use crate::export::export::Exporter;
-use crate::export::stopwatch::{measure, Stopwatch};
-use crate::raytracer::image::Image;
-
-mod raytracer;
-mod animation;
-mod export;
+use bouncing_spheres::animation::animation::{camera_range, make_world, sky};
+use bouncing_spheres::animation::camera_track::CameraTrack;
+use bouncing_spheres::animation::checkpoint::{Checkpoint, save_checkpoint};
+use bouncing_spheres::animation::environment::Environment;
+use bouncing_spheres::animation::physical_sky::PhysicalSkyDescription;
+use bouncing_spheres::animation::physics_cache::{PhysicsCache, PhysicsCacheWriter};
+use bouncing_spheres::animation::scene::Scene;
+use bouncing_spheres::animation::timeline::Timeline;
+use bouncing_spheres::export::export::Exporter;
+use bouncing_spheres::export::stopwatch::{measure, Stopwatch};
+use bouncing_spheres::export::video::VideoSettings;
+use bouncing_spheres::raytracer::light::Sun;
+use bouncing_spheres::raytracer::tone_mapping::ToneMapping;
+use bouncing_spheres::{Image, PhysicsSettings, render, render_aovs, Sky, World};
 
 #[derive(StructOpt)]
 struct Cli {
@@ -29,6 +37,49 @@ struct Cli {
     config_path: String,
     #[structopt(short, long)]
     profile: String,
+    /// Render without opening a window.
+    #[structopt(long)]
+    headless: bool,
+    /// Scene file with the initial conditions. Uses the built-in scene if omitted.
+    #[structopt(long)]
+    scene: Option<String>,
+    /// Save the state of the simulation every N frames.
+    #[structopt(long)]
+    checkpoint_interval: Option<usize>,
+    #[structopt(default_value = "checkpoints", long)]
+    checkpoint_dir: String,
+    /// Continue rendering from a saved state of the simulation (with the same scene).
+    #[structopt(long)]
+    from_checkpoint: Option<String>,
+    /// Export the frames into this directory instead of a new one below `./output`.
+    #[structopt(long)]
+    output_dir: Option<String>,
+    /// First frame to render. The frames before it are only simulated.
+    #[structopt(long)]
+    start_frame: Option<usize>,
+    /// Stop rendering before this frame.
+    #[structopt(long)]
+    end_frame: Option<usize>,
+    /// Skip the frames already present in the output directory.
+    #[structopt(long, requires = "output-dir")]
+    resume: bool,
+    /// Simulate and render every frame in turn if omitted.
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Only run the simulation and write the states of all frames into a physics cache.
+    Simulate {
+        #[structopt(long)]
+        cache: String,
+    },
+    /// Render the frames from a physics cache (of the same scene) instead of simulating them.
+    Render {
+        #[structopt(long)]
+        cache: String,
+    },
 }
 
 #[derive(Debug, Deserialize, Clone, Copy)]
@@ -39,65 +90,316 @@ struct Profile {
     max_depth: usize,
     display_scale_factor: usize,
     export: bool,
+    #[serde(default)]
+    headless: bool,
+    #[serde(default)]
+    tone_mapping: ToneMapping,
+    // Also export the linear radiance of the frames as `.pfm` files.
+    #[serde(default)]
+    export_hdr: bool,
+    // Also export depth, normal, albedo, sphere index and motion passes.
+    #[serde(default)]
+    export_aovs: bool,
+    // Override the timeline of the scene.
+    duration: Option<f64>,
+    fps: Option<f64>,
 }
 
 #[derive(Debug, Deserialize)]
 struct Config {
     profiles: HashMap<String, Profile>,
+    #[serde(default)]
+    video: VideoSettings,
 }
 
-fn load_config() -> Profile {
-    let args = Cli::from_args();
+fn load_config(args: &Cli) -> (Profile, VideoSettings) {
     let mut config_file_content = String::new();
     File::open(&args.config_path).and_then(|mut f| {
         f.read_to_string(&mut config_file_content)
-    }).expect(&format!("Unable to read config file: {}", &args.config_path));
-    *toml::from_str::<Config>(&config_file_content)
-        .expect(&format!("Unable to parse config file: {}", &args.config_path))
-        .profiles.get(&args.profile)
-        .expect(&format!("Unknown profile: {}", &args.profile))
+    }).unwrap_or_else(|_| panic!("Unable to read config file: {}", &args.config_path));
+    let config = toml::from_str::<Config>(&config_file_content)
+        .unwrap_or_else(|e| panic!("Unable to parse config file {}: {}", &args.config_path, e));
+    let profile = *config.profiles.get(&args.profile)
+        .unwrap_or_else(|| panic!("Unknown profile: {}", &args.profile));
+    (Profile {
+        headless: profile.headless || args.headless,
+        ..profile
+    }, config.video)
 }
 
-fn render(profile: Profile) {
-    let mut world = make_world();
+struct Animation {
+    profile: Profile,
+    world: World,
+    camera_track: Option<CameraTrack>,
+    timeline: Timeline,
+    physics: PhysicsSettings,
+    sun: Option<Sun>,
+    environment: Option<Environment>,
+    physical_sky: Option<PhysicalSkyDescription>,
+    cache: Option<PhysicsCache>,
+    exporter: Exporter,
+    checkpoint_interval: Option<usize>,
+    checkpoint_dir: PathBuf,
+    start_frame_num: usize,
+    end_frame_num: usize,
+    resume: bool,
+    frame_num: usize,
+    frame_stopwatch: Instant,
+}
 
-    let canvas = Canvas::new(
-        profile.resolution_x * profile.display_scale_factor,
-        profile.resolution_y * profile.display_scale_factor)
-        .title("bouncing-spheres");
+fn initial_world(scene: Option<Scene>) -> (World, Scene) {
+    match scene {
+        Some(scene) => (scene.make_world(), scene),
+        None => (make_world(), Scene::default()),
+    }
+}
 
-    let exporter = Exporter::new(profile.export);
-    let mut frame_num = 0;
-    let mut frame_stopwatch = Instant::now();
+fn make_timeline(profile: &Profile, scene: &Scene) -> Timeline {
+    let timeline = Timeline {
+        duration: profile.duration.unwrap_or(scene.timeline.duration),
+        fps: profile.fps.unwrap_or(scene.timeline.fps),
+        ..scene.timeline.clone()
+    };
+    // The profile may override the duration and fps checked when loading the scene.
+    timeline.validate().unwrap_or_else(|e| panic!("Invalid timeline: {}", e));
+    timeline
+}
 
-    canvas.render(move |_, image| {
-        let t_real = frame_num as f64 / num_frames() as f64;
-        let t_real_previous_frame = ((frame_num as f64 - 1.0) / num_frames() as f64).max(0.0);
+// Fails before rendering anything if the cache was simulated with a different scene or profile.
+fn check_cache(cache: &PhysicsCache, cache_path: &str, sphere_count: usize, timeline: &Timeline,
+               end_frame_num: usize) {
+    if cache.sphere_count() != sphere_count {
+        panic!("Physics cache {} contains {} spheres, but the scene has {}",
+               cache_path, cache.sphere_count(), sphere_count);
+    }
+    if cache.fps() != timeline.fps || cache.num_frames() != timeline.num_frames() {
+        panic!("Physics cache {} was simulated with {} frames at {} fps, \
+                but the timeline has {} frames at {} fps",
+               cache_path, cache.num_frames(), cache.fps(), timeline.num_frames(), timeline.fps);
+    }
+    if cache.timeline_fingerprint() != timeline.fingerprint() {
+        panic!("Physics cache {} was simulated with a different timeline \
+                (`duration` or `time_remap`)", cache_path);
+    }
+    if cache.frame_count() < end_frame_num {
+        panic!("Physics cache {} only contains {} of the {} frames to render",
+               cache_path, cache.frame_count(), end_frame_num);
+    }
+}
 
-        let (world_advanced, physics_duration) = measure(
-            || world.advance(t_real, t_real_previous_frame, &physics_settings()));
-        world = world_advanced;
+// Fails before simulating anything if the checkpoint was saved with a different timeline.
+fn check_checkpoint(checkpoint: &Checkpoint, checkpoint_path: &str, timeline: &Timeline) {
+    if checkpoint.fps != timeline.fps || checkpoint.num_frames != timeline.num_frames() {
+        panic!("Checkpoint {} was saved with {} frames at {} fps, \
+                but the timeline has {} frames at {} fps",
+               checkpoint_path, checkpoint.num_frames, checkpoint.fps, timeline.num_frames(),
+               timeline.fps);
+    }
+}
 
-        let cams = camera_range(t_real, t_real_previous_frame,
-                                image.width() as f64 / image.height() as f64);
+impl Animation {
+    fn new(profile: Profile, video: VideoSettings, scene: Option<Scene>, args: &Cli)
+           -> Animation {
+        if args.resume && video.stream {
+            panic!("Can not resume when streaming the frames into a video.");
+        }
+        let (world, scene) = initial_world(scene);
+        let timeline = make_timeline(&profile, &scene);
+        let (world, frame_num) = match &args.from_checkpoint {
+            Some(path) => {
+                let checkpoint = Checkpoint::load(Path::new(path));
+                check_checkpoint(&checkpoint, path, &timeline);
+                (checkpoint.world, checkpoint.frame_num)
+            }
+            None => (world, 0),
+        };
+        if args.checkpoint_interval.is_some() {
+            fs::create_dir_all(&args.checkpoint_dir).unwrap_or_else(|_| {
+                panic!("Can not create checkpoint directory: {}", args.checkpoint_dir)
+            });
+        }
+        let physics = scene.physics_settings();
+        let start_frame_num = args.start_frame.unwrap_or(frame_num);
+        if start_frame_num < frame_num {
+            panic!("Can not start with frame {} before the checkpoint at frame {}",
+                   start_frame_num, frame_num);
+        }
+        let end_frame_num = args.end_frame.unwrap_or_else(|| timeline.num_frames())
+            .min(timeline.num_frames());
+        let cache = match &args.command {
+            Some(Command::Render { cache: cache_path }) => {
+                let cache = PhysicsCache::open(Path::new(cache_path));
+                check_cache(&cache, cache_path, world.spheres().len(), &timeline, end_frame_num);
+                Some(cache)
+            }
+            _ => None,
+        };
+        let exporter = Exporter::new(profile.export, args.output_dir.as_deref(), video,
+                                     timeline.fps, profile.export_hdr);
+        let mut animation = Animation {
+            profile,
+            world,
+            camera_track: scene.camera,
+            timeline,
+            physics,
+            sun: scene.sun,
+            environment: scene.environment.as_ref().map(Environment::load),
+            physical_sky: scene.physical_sky,
+            cache,
+            exporter,
+            checkpoint_interval: args.checkpoint_interval,
+            checkpoint_dir: PathBuf::from(&args.checkpoint_dir),
+            start_frame_num,
+            end_frame_num,
+            resume: args.resume,
+            frame_num,
+            frame_stopwatch: Instant::now(),
+        };
+        animation.skip_frames();
+        animation
+    }
+
+    fn sky(&self, t_real: f64) -> Sky {
+        match (&self.physical_sky, &self.environment) {
+            (Some(physical_sky), _) => physical_sky.sky(t_real),
+            (None, Some(environment)) =>
+                Sky { background: environment.background(t_real), sun: self.sun },
+            (None, None) => Sky { sun: self.sun, ..sky(t_real) },
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.frame_num >= self.end_frame_num
+    }
+
+    fn is_skipped(&self, frame_num: usize) -> bool {
+        frame_num < self.start_frame_num || (self.resume && self.exporter.frame_exists(frame_num))
+    }
+
+    /// Moves on to the next frame to render, simulating the ones in between.
+    fn skip_frames(&mut self) {
+        while !self.is_done() && self.is_skipped(self.frame_num) {
+            // The physics cache provides every frame directly.
+            if self.cache.is_none() {
+                self.simulate_frame();
+            }
+            self.frame_num += 1;
+        }
+    }
 
-        let (pixels, render_duration) = measure(|| raytracer::render::render(
+    fn simulate_frame(&mut self) -> Duration {
+        let delta_t = self.timeline.delta_t(self.frame_num);
+
+        // The physics cache does not contain all the state needed for a checkpoint.
+        if let (Some(interval), None) = (self.checkpoint_interval, &self.cache) {
+            if self.frame_num.is_multiple_of(interval) {
+                let path = self.checkpoint_dir.join(format!("{:08}.checkpoint", self.frame_num));
+                info!("Saving {}", path.display());
+                save_checkpoint(&path, self.frame_num, &self.world, self.timeline.fps,
+                                self.timeline.num_frames());
+            }
+        }
+
+        let (world, physics, frame_num) = (&self.world, &self.physics, self.frame_num);
+        let cache = &mut self.cache;
+        let (world_advanced, physics_duration) = measure(|| match cache {
+            Some(cache) => cache.frame(frame_num, world.spheres()),
+            None => world.advance(delta_t, physics),
+        });
+        self.world = world_advanced;
+        physics_duration
+    }
+
+    fn render_next_frame(&mut self) -> Image {
+        let profile = self.profile;
+        let t_real = self.timeline.t_real(self.frame_num);
+        let t_real_previous_frame = self.timeline.t_real_previous_frame(self.frame_num);
+
+        let physics_duration = self.simulate_frame();
+
+        let cams = camera_range(self.camera_track.as_ref(), t_real, t_real_previous_frame,
+                                profile.resolution_x as f64 / profile.resolution_y as f64);
+
+        let world = &self.world;
+        let sky = self.sky(t_real);
+        let (pixels, render_duration) = measure(|| render(
             profile.resolution_x, profile.resolution_y,
-            profile.samples_per_pixel, profile.max_depth, &world,
-            &cams, &sky(t_real)));
+            profile.samples_per_pixel, profile.max_depth, world,
+            &cams, &sky));
 
-        plot_pixels(image, &pixels, profile.display_scale_factor);
-        exporter.process_frame(&pixels, frame_num);
+        let display_pixels = pixels.tone_mapped(&profile.tone_mapping);
+        self.exporter.process_frame(&pixels, &display_pixels, self.frame_num);
+        if profile.export_aovs && self.exporter.is_enabled() {
+            let aovs = render_aovs(profile.resolution_x, profile.resolution_y, &self.world, &cams);
+            self.exporter.process_aovs(&aovs, self.frame_num);
+        }
         info!("Time spent to render the current frame ({}/{}): {} ms (\
-        {} ms physics + {} ms rendering + display)",
-              frame_num + 1, num_frames(),
-              frame_stopwatch.check_and_reset().as_millis(),
+        {} ms physics + {} ms rendering)",
+              self.frame_num + 1, self.timeline.num_frames(),
+              self.frame_stopwatch.check_and_reset().as_millis(),
               physics_duration.as_millis(),
               render_duration.as_millis());
 
-        frame_num += 1;
-        if frame_num >= num_frames() {
-            exporter.combine_frames_to_video();
+        self.frame_num += 1;
+        self.skip_frames();
+        display_pixels
+    }
+
+    fn finish(&mut self) {
+        // Frames before a checkpoint or `--start-frame` may come from an earlier run.
+        self.exporter.finish(0..self.timeline.num_frames());
+    }
+}
+
+fn run(profile: Profile, video: VideoSettings, scene: Option<Scene>, args: &Cli) {
+    match &args.command {
+        Some(Command::Simulate { cache }) => simulate(profile, scene, cache),
+        _ if profile.headless => render_headless(profile, video, scene, args),
+        _ => render_in_window(profile, video, scene, args),
+    }
+}
+
+fn simulate(profile: Profile, scene: Option<Scene>, cache_path: &str) {
+    let (world, scene) = initial_world(scene);
+    let timeline = make_timeline(&profile, &scene);
+    let physics = scene.physics_settings();
+    let mut writer = PhysicsCacheWriter::create(Path::new(cache_path), world.spheres().len(),
+                                                &timeline);
+    let mut stopwatch = Instant::now();
+    (0..timeline.num_frames()).fold(world, |world, frame_num| {
+        let world = world.advance(timeline.delta_t(frame_num), &physics);
+        writer.write_frame(&world);
+        info!("Time spent to simulate the current frame ({}/{}): {} ms",
+              frame_num + 1, timeline.num_frames(), stopwatch.check_and_reset().as_millis());
+        world
+    });
+}
+
+fn render_headless(profile: Profile, video: VideoSettings, scene: Option<Scene>, args: &Cli) {
+    let mut animation = Animation::new(profile, video, scene, args);
+    while !animation.is_done() {
+        animation.render_next_frame();
+    }
+    animation.finish();
+}
+
+fn render_in_window(profile: Profile, video: VideoSettings, scene: Option<Scene>, args: &Cli) {
+    let canvas = Canvas::new(
+        profile.resolution_x * profile.display_scale_factor,
+        profile.resolution_y * profile.display_scale_factor)
+        .title("bouncing-spheres");
+
+    let mut animation = Animation::new(profile, video, scene, args);
+    if animation.is_done() {
+        animation.finish();
+        return;
+    }
+
+    canvas.render(move |_, image| {
+        let pixels = animation.render_next_frame();
+        plot_pixels(image, &pixels, profile.display_scale_factor);
+        if animation.is_done() {
+            animation.finish();
             std::process::exit(0);
         }
     });
@@ -118,5 +420,8 @@ fn plot_pixels(image: &mut CanvasImage, pixels: &Image, scale_factor: usize) {
 fn main()
 {
     simple_logger::init().unwrap();
-    render(load_config());
+    let args = Cli::from_args();
+    let (profile, video) = load_config(&args);
+    let scene = args.scene.as_ref().map(|scene_path| Scene::load(scene_path));
+    run(profile, video, scene, &args);
 }
@@ -0,0 +1,194 @@
+use crate::raytracer::physics::accelerate;
+use crate::raytracer::sphere::Sphere;
+use crate::raytracer::vector3d::{unit_vector, Vector3d};
+
+// Protects against endless subdivision for spheres at the same position.
+const MAX_DEPTH: usize = 32;
+
+struct OctreeNode {
+    center: Vector3d,
+    half_size: f64,
+    mass: f64,
+    center_of_mass: Vector3d,
+    children: Vec<usize>,
+    // Only filled in leaves
+    sphere_indices: Vec<usize>,
+}
+
+/// Octree over the sphere centers, storing the total mass
+/// and the center of mass of each cell.
+pub struct Octree {
+    nodes: Vec<OctreeNode>,
+}
+
+fn octant(center: &Vector3d, position: &Vector3d) -> usize {
+    (if position.x >= center.x { 1 } else { 0 }) +
+        (if position.y >= center.y { 2 } else { 0 }) +
+        (if position.z >= center.z { 4 } else { 0 })
+}
+
+fn octant_center(center: &Vector3d, half_size: f64, octant: usize) -> Vector3d {
+    let offset = |bit: usize| if octant & bit != 0 { half_size / 2.0 } else { -half_size / 2.0 };
+    *center + &Vector3d { x: offset(1), y: offset(2), z: offset(4) }
+}
+
+impl Octree {
+    pub fn new(spheres: &[Sphere]) -> Octree {
+        let mut octree = Octree { nodes: vec![] };
+        if spheres.is_empty() {
+            return octree;
+        }
+        let (min, max) = spheres.iter().fold(
+            (spheres[0].center, spheres[0].center),
+            |(min, max), s| (
+                Vector3d { x: min.x.min(s.center.x), y: min.y.min(s.center.y), z: min.z.min(s.center.z) },
+                Vector3d { x: max.x.max(s.center.x), y: max.y.max(s.center.y), z: max.z.max(s.center.z) },
+            ));
+        let extent = max - &min;
+        let half_size = extent.x.max(extent.y).max(extent.z) / 2.0 + 1e-9;
+        octree.build(spheres, (0..spheres.len()).collect(), (min + &max) / 2.0, half_size, 0);
+        octree
+    }
+
+    // Returns the index of the new node.
+    fn build(&mut self, spheres: &[Sphere], sphere_indices: Vec<usize>,
+             center: Vector3d, half_size: f64, depth: usize) -> usize {
+        let node_index = self.nodes.len();
+        let mass: f64 = sphere_indices.iter().map(|i| spheres[*i].mass).sum();
+        let center_of_mass = sphere_indices.iter()
+            .fold(Vector3d::null(), |acc, i| acc + &(spheres[*i].center * spheres[*i].mass))
+            / mass;
+        self.nodes.push(OctreeNode {
+            center,
+            half_size,
+            mass,
+            center_of_mass,
+            children: vec![],
+            sphere_indices: vec![],
+        });
+        if sphere_indices.len() <= 1 || depth >= MAX_DEPTH {
+            self.nodes[node_index].sphere_indices = sphere_indices;
+            return node_index;
+        }
+        let mut octants: Vec<Vec<usize>> = vec![vec![]; 8];
+        for i in sphere_indices {
+            octants[octant(&center, &spheres[i].center)].push(i);
+        }
+        let children = octants.into_iter().enumerate()
+            .filter(|(_, indices)| !indices.is_empty())
+            .map(|(o, indices)| self.build(
+                spheres, indices, octant_center(&center, half_size, o), half_size / 2.0, depth + 1))
+            .collect();
+        self.nodes[node_index].children = children;
+        node_index
+    }
+
+    fn contains(node: &OctreeNode, position: &Vector3d) -> bool {
+        (position.x - node.center.x).abs() <= node.half_size &&
+            (position.y - node.center.y).abs() <= node.half_size &&
+            (position.z - node.center.z).abs() <= node.half_size
+    }
+
+    /// Gravitational acceleration on the sphere with the given index.
+    /// Cells appearing smaller than `opening_angle` (size / distance)
+    /// are approximated by their center of mass.
+    pub fn acceleration(&self, spheres: &[Sphere], sphere_index: usize,
+                        gravity_constant: f64, opening_angle: f64) -> Vector3d {
+        let position = spheres[sphere_index].center;
+        let pull = |mass: f64, other: &Vector3d| {
+            let diff = *other - &position;
+            unit_vector(&diff) * gravity_constant * mass / diff.length_squared()
+        };
+        let mut acceleration = Vector3d::null();
+        let mut stack = if self.nodes.is_empty() { vec![] } else { vec![0] };
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if node.children.is_empty() {
+                for i in node.sphere_indices.iter().filter(|i| **i != sphere_index) {
+                    acceleration = acceleration + &pull(spheres[*i].mass, &spheres[*i].center);
+                }
+            } else if !Octree::contains(node, &position) &&
+                2.0 * node.half_size < opening_angle * (node.center_of_mass - &position).length() {
+                acceleration = acceleration + &pull(node.mass, &node.center_of_mass);
+            } else {
+                stack.extend(node.children.iter());
+            }
+        }
+        acceleration
+    }
+}
+
+/// Approximation of `physics::gravity_accelerations` in O(n log n)
+pub fn accelerations_barnes_hut(spheres: &[Sphere], gravity_constant: f64,
+                                opening_angle: f64) -> Vec<Vector3d> {
+    let octree = Octree::new(spheres);
+    (0..spheres.len()).map(|i| {
+        octree.acceleration(spheres, i, gravity_constant, opening_angle)
+    }).collect()
+}
+
+/// Approximation of `physics::gravitate` in O(n log n)
+pub fn gravitate_barnes_hut(spheres: &[Sphere], delta_t: f64, gravity_constant: f64,
+                            opening_angle: f64) -> Vec<Sphere> {
+    accelerate(spheres, &accelerations_barnes_hut(spheres, gravity_constant, opening_angle),
+               delta_t)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::prelude::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use crate::raytracer::color::Color;
+    use crate::raytracer::material::Material;
+    use crate::raytracer::physics::gravitate;
+    use crate::raytracer::quaternion::Quaternion;
+    use crate::raytracer::texture::Texture;
+
+    use super::*;
+
+    #[test]
+    fn test_barnes_hut_error_is_small() {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let spheres: Vec<Sphere> = (0..1000).map(|_| {
+            let center = Vector3d {
+                x: rng.gen_range(-50.0, 50.0),
+                y: rng.gen_range(-50.0, 50.0),
+                z: rng.gen_range(-50.0, 50.0),
+            };
+            Sphere {
+                center,
+                radius: 1.0,
+                material: Material {
+                    albedo: Texture::Solid(Color::black()),
+                    reflectiveness: 0.0,
+                    reflection_fuzz: 0.0,
+                    transparency: 0.0,
+                    refraction_index: 1.5,
+                    absorption: Color::black(),
+                    emission: Color::black(),
+                    flash_emission: 0.0,
+                },
+                speed: Vector3d::null(),
+                mass: rng.gen_range(0.1, 3.0),
+                extra_brightness: 0.0,
+                center_old: center,
+                angular_velocity: Vector3d::null(),
+                orientation: Quaternion::identity(),
+                orientation_old: Quaternion::identity(),
+            }
+        }).collect();
+        let exact = gravitate(&spheres, 0.1, 0.73);
+        let approximated = gravitate_barnes_hut(&spheres, 0.1, 0.73, 0.5);
+        // Relative to the mean acceleration, because in the middle of the cloud
+        // the pulls almost cancel each other out.
+        let mean_speed = exact.iter().map(|s| s.speed.length()).sum::<f64>() / exact.len() as f64;
+        let errors: Vec<f64> = exact.iter().zip(approximated.iter())
+            .map(|(e, a)| (e.speed - &a.speed).length() / mean_speed)
+            .collect();
+        let mean_error = errors.iter().sum::<f64>() / errors.len() as f64;
+        let max_error = errors.iter().cloned().fold(0.0, f64::max);
+        assert!(mean_error < 0.01, "mean relative error: {}", mean_error);
+        assert!(max_error < 0.05, "max relative error: {}", max_error);
+    }
+}
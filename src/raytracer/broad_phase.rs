@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+
+use crate::raytracer::sphere::Sphere;
+
+// Spheres much larger than the typical one (like the planet) would make the grid cells huge,
+// so they are paired with all other spheres instead of being put into the grid.
+const HUGE_SPHERE_RADIUS_FACTOR: f64 = 8.0;
+
+type Cell = (i64, i64, i64);
+
+fn median_radius(spheres: &[Sphere]) -> f64 {
+    let mut radii: Vec<f64> = spheres.iter().map(|s| s.radius).collect();
+    radii.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    radii[radii.len() / 2]
+}
+
+fn cell_of(sphere: &Sphere, cell_size: f64) -> Cell {
+    (
+        (sphere.center.x / cell_size).floor() as i64,
+        (sphere.center.y / cell_size).floor() as i64,
+        (sphere.center.z / cell_size).floor() as i64,
+    )
+}
+
+/// Index pairs `(i, j)` with `i < j` of all spheres that might overlap,
+/// sorted in the same order as `Itertools::combinations(2)` would produce them.
+/// Uses a uniform grid with cells of the diameter of the largest regular sphere.
+pub fn candidate_pairs(spheres: &[Sphere]) -> Vec<(usize, usize)> {
+    if spheres.len() < 2 {
+        return vec![];
+    }
+    let huge_radius = HUGE_SPHERE_RADIUS_FACTOR * median_radius(spheres);
+    let is_huge = |s: &Sphere| s.radius > huge_radius;
+    let cell_size = 2.0 * spheres.iter()
+        .filter(|s| !is_huge(s))
+        .map(|s| s.radius)
+        .fold(0.0_f64, f64::max)
+        .max(f64::MIN_POSITIVE);
+
+    let mut grid: HashMap<Cell, Vec<usize>> = HashMap::new();
+    for (i, sphere) in spheres.iter().enumerate() {
+        if !is_huge(sphere) {
+            grid.entry(cell_of(sphere, cell_size)).or_default().push(i);
+        }
+    }
+
+    let mut pairs = vec![];
+    for (i, sphere) in spheres.iter().enumerate() {
+        if is_huge(sphere) {
+            pairs.extend((0..spheres.len()).filter(|j| *j != i).map(|j| (i.min(j), i.max(j))));
+            continue;
+        }
+        let (x, y, z) = cell_of(sphere, cell_size);
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    if let Some(others) = grid.get(&(x + dx, y + dy, z + dz)) {
+                        pairs.extend(others.iter().filter(|j| **j > i).map(|j| (i, *j)));
+                    }
+                }
+            }
+        }
+    }
+    pairs.sort_unstable();
+    pairs.dedup();
+    pairs
+}
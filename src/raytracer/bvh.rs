@@ -0,0 +1,169 @@
+use crate::raytracer::hit::Hit;
+use crate::raytracer::ray::Ray;
+use crate::raytracer::sphere::Sphere;
+use crate::raytracer::vector3d::Vector3d;
+
+const MAX_SPHERES_PER_LEAF: usize = 2;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Aabb {
+    pub min: Vector3d,
+    pub max: Vector3d,
+}
+
+impl Aabb {
+    pub fn surrounding(a: &Aabb, b: &Aabb) -> Aabb {
+        Aabb {
+            min: Vector3d {
+                x: a.min.x.min(b.min.x),
+                y: a.min.y.min(b.min.y),
+                z: a.min.z.min(b.min.z),
+            },
+            max: Vector3d {
+                x: a.max.x.max(b.max.x),
+                y: a.max.y.max(b.max.y),
+                z: a.max.z.max(b.max.z),
+            },
+        }
+    }
+
+    // Covers the whole movement during the frame, as needed for motion blur.
+    pub fn of_sphere(sphere: &Sphere) -> Aabb {
+        let r = Vector3d { x: sphere.radius, y: sphere.radius, z: sphere.radius };
+        Aabb::surrounding(
+            &Aabb { min: sphere.center - &r, max: sphere.center + &r },
+            &Aabb { min: sphere.center_old - &r, max: sphere.center_old + &r },
+        )
+    }
+
+    pub fn centroid(&self) -> Vector3d {
+        (self.min + &self.max) / 2.0
+    }
+
+    #[inline(always)]
+    pub fn hit(&self, ray: &Ray, inverse_direction: &Vector3d, t_min: f64, t_max: f64) -> bool {
+        let mut t_min = t_min;
+        let mut t_max = t_max;
+        for (origin, inverse_direction, min, max) in &[
+            (ray.origin.x, inverse_direction.x, self.min.x, self.max.x),
+            (ray.origin.y, inverse_direction.y, self.min.y, self.max.y),
+            (ray.origin.z, inverse_direction.z, self.min.z, self.max.z),
+        ] {
+            let t0 = (min - origin) * inverse_direction;
+            let t1 = (max - origin) * inverse_direction;
+            let (t0, t1) = if *inverse_direction < 0.0 { (t1, t0) } else { (t0, t1) };
+            t_min = t0.max(t_min);
+            t_max = t1.min(t_max);
+            if t_max < t_min {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+enum BvhNodeContent {
+    // Range in `Bvh::sphere_indices`
+    Leaf { first: usize, count: usize },
+    Inner { left: usize, right: usize },
+}
+
+struct BvhNode {
+    aabb: Aabb,
+    content: BvhNodeContent,
+}
+
+/// Bounding volume hierarchy over the spheres of one frame.
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    sphere_indices: Vec<usize>,
+}
+
+fn axis_value(v: &Vector3d, axis: usize) -> f64 {
+    match axis {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z,
+    }
+}
+
+impl Bvh {
+    pub fn new(spheres: &[Sphere]) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: vec![],
+            sphere_indices: (0..spheres.len()).collect(),
+        };
+        if !spheres.is_empty() {
+            let aabbs: Vec<Aabb> = spheres.iter().map(Aabb::of_sphere).collect();
+            bvh.build(&aabbs, 0, spheres.len());
+        }
+        bvh
+    }
+
+    // Returns the index of the new node.
+    fn build(&mut self, aabbs: &[Aabb], first: usize, count: usize) -> usize {
+        let indices = &mut self.sphere_indices[first..first + count];
+        let aabb = indices.iter().skip(1).fold(aabbs[indices[0]], |a, i| {
+            Aabb::surrounding(&a, &aabbs[*i])
+        });
+        let node_index = self.nodes.len();
+        self.nodes.push(BvhNode { aabb, content: BvhNodeContent::Leaf { first, count } });
+        if count <= MAX_SPHERES_PER_LEAF {
+            return node_index;
+        }
+
+        let extent = aabb.max - &aabb.min;
+        let axis = if extent.x > extent.y && extent.x > extent.z {
+            0
+        } else if extent.y > extent.z {
+            1
+        } else {
+            2
+        };
+        indices.sort_by(|a, b| {
+            axis_value(&aabbs[*a].centroid(), axis)
+                .partial_cmp(&axis_value(&aabbs[*b].centroid(), axis))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        let count_left = count / 2;
+        let left = self.build(aabbs, first, count_left);
+        let right = self.build(aabbs, first + count_left, count - count_left);
+        self.nodes[node_index].content = BvhNodeContent::Inner { left, right };
+        node_index
+    }
+
+    pub fn hit(&self, spheres: &[Sphere], ray: &Ray, t_min: f64, t_max: f64) -> Option<Hit> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let inverse_direction = Vector3d {
+            x: 1.0 / ray.direction.x,
+            y: 1.0 / ray.direction.y,
+            z: 1.0 / ray.direction.z,
+        };
+        let mut closest_so_far = t_max;
+        let mut rec: Option<Hit> = None;
+        let mut stack = vec![0];
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if !node.aabb.hit(ray, &inverse_direction, t_min, closest_so_far) {
+                continue;
+            }
+            match node.content {
+                BvhNodeContent::Leaf { first, count } => {
+                    for i in &self.sphere_indices[first..first + count] {
+                        if let Some(temp_rec) = spheres[*i].hit(ray, t_min, closest_so_far) {
+                            closest_so_far = temp_rec.t;
+                            rec = Some(Hit { sphere_index: Some(*i), ..temp_rec });
+                        }
+                    }
+                }
+                BvhNodeContent::Inner { left, right } => {
+                    stack.push(right);
+                    stack.push(left);
+                }
+            }
+        }
+        rec
+    }
+}
@@ -27,6 +27,17 @@ pub fn get_ray_camera_blend(rng: &mut StdRng,
     let frame_time = rng.gen_range(0.0_f64, 1.0_f64);
     let rd = random_in_unit_disk(rng) *
         blend(cams.cam_b.lens_radius, cams.cam_a.lens_radius, frame_time);
+    blend_rays(horizontal_fraction, vertical_fraction, rd, frame_time, cams)
+}
+
+/// Ray through the center of the lens, i.e., without depth of field
+pub fn get_pinhole_ray_camera_blend(horizontal_fraction: f64, vertical_fraction: f64,
+                                    frame_time: f64, cams: &CameraRange) -> Ray {
+    blend_rays(horizontal_fraction, vertical_fraction, Vector3d::null(), frame_time, cams)
+}
+
+fn blend_rays(horizontal_fraction: f64, vertical_fraction: f64,
+              rd: Vector3d, frame_time: f64, cams: &CameraRange) -> Ray {
     let ray_a = cams.cam_a.get_ray_rd(horizontal_fraction, vertical_fraction, rd, frame_time);
     let ray_b = cams.cam_b.get_ray_rd(horizontal_fraction, vertical_fraction, rd, frame_time);
     Ray {
@@ -65,7 +76,7 @@ impl Camera {
         let viewport_width = aspect_ratio * viewport_height;
 
         let w = unit_vector(&((*position) - looks_at));
-        let u = unit_vector(&cross(&up_direction, &w));
+        let u = unit_vector(&cross(up_direction, &w));
         let v = cross(&w, &u);
 
         let origin = *position;
@@ -2,7 +2,7 @@ use std::ops::{Add, Div, Mul};
 
 use pixel_canvas::Color as CanvasColor;
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct Color {
     pub r: f64,
     pub g: f64,
@@ -10,18 +10,19 @@ pub struct Color {
 }
 
 impl Color {
-    pub fn sqrt_gamma_correct(&self) -> Color {
+    /// Exact sRGB transfer function (of the values clamped to `[0, 1]`)
+    pub fn srgb_encoded(&self) -> Color {
         Color {
-            r: self.r.sqrt(),
-            g: self.g.sqrt(),
-            b: self.b.sqrt(),
+            r: srgb_encode(self.r),
+            g: srgb_encode(self.g),
+            b: srgb_encode(self.b),
         }
     }
 
-    pub fn to_canvas_color(&self) -> CanvasColor {
-        let r = (self.r.max(0.0).min(1.0) * 255.0) as u8;
-        let g = (self.g.max(0.0).min(1.0) * 255.0) as u8;
-        let b = (self.b.max(0.0).min(1.0) * 255.0) as u8;
+    pub fn to_canvas_color(self) -> CanvasColor {
+        let r = (self.r.clamp(0.0, 1.0) * 255.0).round() as u8;
+        let g = (self.g.clamp(0.0, 1.0) * 255.0).round() as u8;
+        let b = (self.b.clamp(0.0, 1.0) * 255.0).round() as u8;
         CanvasColor { r, g, b }
     }
 
@@ -29,6 +30,11 @@ impl Color {
     {
         Color { r: 0.0, g: 0.0, b: 0.0 }
     }
+
+    pub fn white() -> Color
+    {
+        Color { r: 1.0, g: 1.0, b: 1.0 }
+    }
 }
 
 impl Mul<f64> for Color {
@@ -63,6 +69,23 @@ impl Add<&Color> for Color {
     }
 }
 
+pub fn srgb_encode(linear: f64) -> f64 {
+    let linear = linear.clamp(0.0, 1.0);
+    if linear <= 0.003_130_8 {
+        12.92 * linear
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+pub fn srgb_decode(encoded: f64) -> f64 {
+    if encoded <= 0.040_45 {
+        encoded / 12.92
+    } else {
+        ((encoded + 0.055) / 1.055).powf(2.4)
+    }
+}
+
 pub fn blend_colors(col1: &Color, col2: &Color, factor_col1: f64) -> Color {
     *col1 * factor_col1 + &(*col2 * (1.0 - factor_col1))
 }
@@ -80,4 +103,17 @@ mod tests {
         );
         assert_eq!(result, Color { r: 0.3, g: 0.7, b: 0.0 });
     }
+
+    #[test]
+    fn test_srgb() {
+        assert_eq!(srgb_encode(0.0), 0.0);
+        assert!((srgb_encode(1.0) - 1.0).abs() < 1e-12);
+        assert_eq!(srgb_encode(7.0), srgb_encode(1.0));
+        assert!((srgb_encode(0.5) - 0.735_357).abs() < 1e-6);
+        assert!((srgb_encode(0.001) - 0.012_92).abs() < 1e-9);
+        for i in 0..=100 {
+            let value = i as f64 / 100.0;
+            assert!((srgb_decode(srgb_encode(value)) - value).abs() < 1e-12);
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,379 @@
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+use rand::prelude::StdRng;
+use rand::Rng;
+
+use crate::raytracer::color::Color;
+use crate::raytracer::vector3d::Vector3d;
+
+/// Equirectangular image of the radiance arriving from all directions,
+/// with the rows ordered from top (+y) to bottom (-y).
+pub struct EnvironmentMap {
+    width: usize,
+    height: usize,
+    data: Vec<Color>,
+    // Cumulative distributions of the luminance (weighted by the texel solid angle)
+    // for importance sampling: one over the rows and one over the columns of each row.
+    marginal_cdf: Vec<f64>,
+    conditional_cdfs: Vec<Vec<f64>>,
+    power: f64,
+}
+
+/// An environment map as part of the sky, rotated around the y-axis.
+pub struct EnvironmentLayer {
+    pub map: Arc<EnvironmentMap>,
+    // In radians
+    pub rotation: f64,
+    pub intensity: f64,
+}
+
+fn luminance(c: &Color) -> f64 {
+    0.2126 * c.r + 0.7152 * c.g + 0.0722 * c.b
+}
+
+fn cdf(values: &[f64]) -> Vec<f64> {
+    let mut result = Vec::with_capacity(values.len() + 1);
+    let mut sum = 0.0;
+    result.push(0.0);
+    for value in values {
+        sum += value;
+        result.push(sum);
+    }
+    result
+}
+
+// Index of the interval of `cdf` containing `x`, and the position within it in [0, 1)
+fn sample_cdf(cdf: &[f64], x: f64) -> (usize, f64) {
+    let target = x * cdf[cdf.len() - 1];
+    let i = match cdf.binary_search_by(|v| v.partial_cmp(&target).unwrap()) {
+        Ok(i) => i,
+        Err(i) => i - 1,
+    }.min(cdf.len() - 2);
+    let width = cdf[i + 1] - cdf[i];
+    let offset = if width > 0.0 { (target - cdf[i]) / width } else { 0.5 };
+    (i, offset.clamp(0.0, 0.999999))
+}
+
+fn rotate_y(v: &Vector3d, angle: f64) -> Vector3d {
+    let (sin, cos) = angle.sin_cos();
+    Vector3d { x: cos * v.x + sin * v.z, y: v.y, z: -sin * v.x + cos * v.z }
+}
+
+impl EnvironmentMap {
+    pub fn new(width: usize, height: usize, data: Vec<Color>) -> EnvironmentMap {
+        assert_eq!(data.len(), width * height);
+        let row_weights: Vec<Vec<f64>> = (0..height).map(|y| {
+            let sin_theta = (PI * (y as f64 + 0.5) / height as f64).sin();
+            (0..width).map(|x| luminance(&data[y * width + x]).max(0.0) * sin_theta).collect()
+        }).collect();
+        let conditional_cdfs: Vec<Vec<f64>> = row_weights.iter().map(|w| cdf(w)).collect();
+        let marginal_cdf = cdf(&conditional_cdfs.iter()
+            .map(|c| c[c.len() - 1]).collect::<Vec<f64>>());
+        let power = marginal_cdf[height] * 2.0 * PI * PI / (width * height) as f64;
+        EnvironmentMap { width, height, data, marginal_cdf, conditional_cdfs, power }
+    }
+
+    /// Reads a Radiance (`.hdr`) or Portable Float Map (`.pfm`) image.
+    pub fn load(path: &Path) -> EnvironmentMap {
+        let file = File::open(path)
+            .unwrap_or_else(|_| panic!("Unable to open environment map {}", path.display()));
+        let mut reader = BufReader::new(file);
+        let is_pfm = path.extension().is_some_and(|e| e.eq_ignore_ascii_case("pfm"));
+        let result = if is_pfm { read_pfm(&mut reader) } else { read_radiance_hdr(&mut reader) };
+        let (width, height, data) = result
+            .unwrap_or_else(|e| panic!("Unable to read environment map {}: {}", path.display(), e));
+        // The sampling binary-searches the summed up luminance, which must not be NaN.
+        let is_finite = |c: &Color| c.r.is_finite() && c.g.is_finite() && c.b.is_finite();
+        if let Some(i) = data.iter().position(|c| !is_finite(c)) {
+            panic!("Environment map {} contains a non-finite value at texel ({}, {})",
+                   path.display(), i % width, i / width);
+        }
+        EnvironmentMap::new(width, height, data)
+    }
+
+    // Integral of the luminance over all directions
+    pub fn power(&self) -> f64 {
+        self.power
+    }
+
+    fn texel(&self, direction: &Vector3d) -> (usize, usize) {
+        let length = direction.length();
+        let theta = (direction.y / length).clamp(-1.0, 1.0).acos();
+        let phi = direction.z.atan2(direction.x) + PI;
+        let x = ((phi / (2.0 * PI) * self.width as f64) as usize).min(self.width - 1);
+        let y = ((theta / PI * self.height as f64) as usize).min(self.height - 1);
+        (x, y)
+    }
+
+    pub fn radiance(&self, direction: &Vector3d) -> Color {
+        let (x, y) = self.texel(direction);
+        self.data[y * self.width + x]
+    }
+
+    // Probability density (per solid angle) of `sample` returning the given direction
+    pub fn pdf(&self, direction: &Vector3d) -> f64 {
+        let total = self.marginal_cdf[self.height];
+        if total <= 0.0 {
+            return 0.0;
+        }
+        let (x, y) = self.texel(direction);
+        let row = &self.conditional_cdfs[y];
+        let texel_probability = (row[x + 1] - row[x]) / total;
+        let sin_theta = (PI * (y as f64 + 0.5) / self.height as f64).sin();
+        let texel_solid_angle = 2.0 * PI * PI * sin_theta / (self.width * self.height) as f64;
+        texel_probability / texel_solid_angle
+    }
+
+    /// Random direction, preferring bright parts of the map
+    pub fn sample(&self, rng: &mut StdRng) -> Vector3d {
+        let (y, y_offset) = sample_cdf(&self.marginal_cdf, rng.gen());
+        let (x, x_offset) = sample_cdf(&self.conditional_cdfs[y], rng.gen());
+        let theta = PI * (y as f64 + y_offset) / self.height as f64;
+        let phi = 2.0 * PI * (x as f64 + x_offset) / self.width as f64 - PI;
+        Vector3d {
+            x: theta.sin() * phi.cos(),
+            y: theta.cos(),
+            z: theta.sin() * phi.sin(),
+        }
+    }
+}
+
+impl EnvironmentLayer {
+    fn weight(&self) -> f64 {
+        self.intensity * self.map.power()
+    }
+
+    pub fn radiance(&self, direction: &Vector3d) -> Color {
+        self.map.radiance(&rotate_y(direction, -self.rotation)) * self.intensity
+    }
+}
+
+pub fn layers_radiance(layers: &[EnvironmentLayer], direction: &Vector3d) -> Color {
+    layers.iter().fold(Color::black(), |sum, layer| sum + &layer.radiance(direction))
+}
+
+// Probability density of `sample_layers` returning the given direction
+pub fn layers_pdf(layers: &[EnvironmentLayer], direction: &Vector3d) -> f64 {
+    let total_weight: f64 = layers.iter().map(|l| l.weight()).sum();
+    if total_weight <= 0.0 {
+        return 0.0;
+    }
+    layers.iter().map(|layer| {
+        layer.weight() / total_weight * layer.map.pdf(&rotate_y(direction, -layer.rotation))
+    }).sum()
+}
+
+/// Picks one layer by its brightness, and samples a direction from it.
+pub fn sample_layers(rng: &mut StdRng, layers: &[EnvironmentLayer]) -> Option<Vector3d> {
+    let weights: Vec<f64> = layers.iter().map(|l| l.weight()).collect();
+    let total_weight: f64 = weights.iter().sum();
+    if total_weight <= 0.0 {
+        return None;
+    }
+    let (i, _) = sample_cdf(&cdf(&weights), rng.gen());
+    let layer = &layers[i];
+    Some(rotate_y(&layer.map.sample(rng), layer.rotation))
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_header_line<R: BufRead>(reader: &mut R) -> std::io::Result<String> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
+
+pub(crate) fn read_pfm<R: BufRead>(reader: &mut R) -> std::io::Result<(usize, usize, Vec<Color>)> {
+    let channels = match read_header_line(reader)?.as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(invalid_data("not a PFM file")),
+    };
+    let dimensions = read_header_line(reader)?;
+    let mut dimensions = dimensions.split_whitespace().map(|s| s.parse::<usize>());
+    let (width, height) = match (dimensions.next(), dimensions.next()) {
+        (Some(Ok(w)), Some(Ok(h))) => (w, h),
+        _ => return Err(invalid_data("invalid PFM dimensions")),
+    };
+    let scale = read_header_line(reader)?.parse::<f64>()
+        .map_err(|_| invalid_data("invalid PFM scale"))?;
+    let mut bytes = vec![0; width * height * channels * 4];
+    reader.read_exact(&mut bytes)?;
+    let values: Vec<f64> = bytes.chunks(4).map(|b| {
+        let b = [b[0], b[1], b[2], b[3]];
+        let value = if scale < 0.0 { f32::from_le_bytes(b) } else { f32::from_be_bytes(b) };
+        value as f64
+    }).collect();
+    // The rows of a PFM file go from bottom to top.
+    let data = (0..height).rev().flat_map(|y| {
+        let row = &values[y * width * channels..(y + 1) * width * channels];
+        row.chunks(channels).map(|c| Color {
+            r: c[0],
+            g: c[channels / 2],
+            b: c[channels - 1],
+        }).collect::<Vec<Color>>()
+    }).collect();
+    Ok((width, height, data))
+}
+
+fn rgbe_to_color(rgbe: &[u8]) -> Color {
+    if rgbe[3] == 0 {
+        return Color::black();
+    }
+    let f = 2.0_f64.powi(rgbe[3] as i32 - 136);
+    Color { r: rgbe[0] as f64 * f, g: rgbe[1] as f64 * f, b: rgbe[2] as f64 * f }
+}
+
+fn read_rle_scanline<R: Read>(reader: &mut R, width: usize) -> std::io::Result<Vec<u8>> {
+    // Channels are stored separately, each run-length encoded.
+    let mut channels = vec![0; width * 4];
+    for channel in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let mut count = [0; 1];
+            reader.read_exact(&mut count)?;
+            if count[0] > 128 {
+                let run = (count[0] - 128) as usize;
+                let mut value = [0; 1];
+                reader.read_exact(&mut value)?;
+                if x + run > width {
+                    return Err(invalid_data("bad run length in HDR file"));
+                }
+                for i in 0..run {
+                    channels[(x + i) * 4 + channel] = value[0];
+                }
+                x += run;
+            } else {
+                let run = count[0] as usize;
+                if run == 0 || x + run > width {
+                    return Err(invalid_data("bad run length in HDR file"));
+                }
+                let mut values = vec![0; run];
+                reader.read_exact(&mut values)?;
+                for (i, value) in values.iter().enumerate() {
+                    channels[(x + i) * 4 + channel] = *value;
+                }
+                x += run;
+            }
+        }
+    }
+    Ok(channels)
+}
+
+fn read_radiance_hdr<R: BufRead>(reader: &mut R) -> std::io::Result<(usize, usize, Vec<Color>)> {
+    if !read_header_line(reader)?.starts_with("#?") {
+        return Err(invalid_data("not a Radiance HDR file"));
+    }
+    loop {
+        let line = read_header_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with("FORMAT=") && line != "FORMAT=32-bit_rle_rgbe" {
+            return Err(invalid_data("unsupported HDR pixel format"));
+        }
+    }
+    let resolution = read_header_line(reader)?;
+    let parts: Vec<&str> = resolution.split_whitespace().collect();
+    let (height, width) = match parts.as_slice() {
+        ["-Y", h, "+X", w] => (h.parse::<usize>(), w.parse::<usize>()),
+        _ => return Err(invalid_data("unsupported HDR orientation")),
+    };
+    let (height, width) = match (height, width) {
+        (Ok(h), Ok(w)) => (h, w),
+        _ => return Err(invalid_data("invalid HDR resolution")),
+    };
+    let mut data = Vec::with_capacity(width * height);
+    for _ in 0..height {
+        let mut start = [0; 4];
+        reader.read_exact(&mut start)?;
+        let is_rle = start[0] == 2 && start[1] == 2 && start[2] & 0x80 == 0 &&
+            (8..0x8000).contains(&width) &&
+            ((start[2] as usize) << 8 | start[3] as usize) == width;
+        let scanline = if is_rle {
+            read_rle_scanline(reader, width)?
+        } else {
+            let mut rest = vec![0; (width - 1) * 4];
+            reader.read_exact(&mut rest)?;
+            [start.to_vec(), rest].concat()
+        };
+        data.extend(scanline.chunks(4).map(rgbe_to_color));
+    }
+    Ok((width, height, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use crate::raytracer::image::Image;
+    use crate::raytracer::vector3d::random_unit_vector;
+
+    use super::*;
+
+    fn test_map() -> EnvironmentMap {
+        let (width, height) = (16, 8);
+        EnvironmentMap::new(width, height, (0..width * height).map(|i| {
+            let v = if i == 21 { 100.0 } else { (i % 7) as f64 * 0.1 };
+            Color { r: v, g: v, b: v }
+        }).collect())
+    }
+
+    #[test]
+    fn test_pdf_integrates_to_one() {
+        let map = test_map();
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let num_samples = 200000;
+        let integral = (0..num_samples)
+            .map(|_| map.pdf(&random_unit_vector(&mut rng)) * 4.0 * PI)
+            .sum::<f64>() / num_samples as f64;
+        assert!((integral - 1.0).abs() < 0.02, "{}", integral);
+    }
+
+    #[test]
+    fn test_samples_prefer_bright_texels() {
+        let map = test_map();
+        let mut rng: StdRng = SeedableRng::seed_from_u64(1);
+        let bright = (0..1000)
+            .filter(|_| map.texel(&map.sample(&mut rng)) == (5, 1))
+            .count();
+        assert!(bright > 500);
+    }
+
+    #[test]
+    #[should_panic(expected = "non-finite value at texel (1, 0)")]
+    fn test_reject_non_finite_texels() {
+        let path = std::env::temp_dir()
+            .join(format!("bouncing_spheres_test_non_finite_{}.pfm", std::process::id()));
+        let nan = Color { r: f64::NAN, g: 0.0, b: 0.0 };
+        Image { data: vec![Color::white(), nan], width: 2, height: 1 }.save_pfm(&path);
+        EnvironmentMap::load(&path);
+    }
+
+    #[test]
+    fn test_read_pfm() {
+        let mut bytes = b"PF\n2 1\n-1.0\n".to_vec();
+        for v in &[1.0_f32, 2.0, 3.0, 4.0, 5.0, 6.0] {
+            bytes.extend_from_slice(&v.to_le_bytes());
+        }
+        let (width, height, data) = read_pfm(&mut &bytes[..]).unwrap();
+        assert_eq!((width, height), (2, 1));
+        assert_eq!(data[1], Color { r: 4.0, g: 5.0, b: 6.0 });
+    }
+
+    #[test]
+    fn test_read_radiance_hdr() {
+        let mut bytes = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 2\n".to_vec();
+        bytes.extend_from_slice(&[128, 64, 0, 129, 0, 0, 0, 0]);
+        let (width, height, data) = read_radiance_hdr(&mut &bytes[..]).unwrap();
+        assert_eq!((width, height), (2, 1));
+        assert_eq!(data[0], Color { r: 1.0, g: 0.5, b: 0.0 });
+        assert_eq!(data[1], Color::black());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::raytracer::color::Color;
 use crate::raytracer::material::Material;
 use crate::raytracer::ray::Ray;
 use crate::raytracer::vector3d::{dot, Vector3d};
@@ -8,6 +9,13 @@ pub struct Hit {
     pub normal: Vector3d,
     pub front_face: bool,
     pub material: Material,
+    // Spherical texture coordinates
+    pub u: f64,
+    pub v: f64,
+    // Material albedo looked up in its texture
+    pub albedo: Color,
+    // Position in `World::spheres`, only known to `World::hit`
+    pub sphere_index: Option<usize>,
 }
 
 #[inline(always)]
@@ -16,7 +24,7 @@ pub fn face_normal(r: &Ray, outward_normal: &Vector3d) -> (bool, Vector3d) {
     let normal = if front_face {
         *outward_normal
     } else {
-        Vector3d::null() - &outward_normal
+        Vector3d::null() - outward_normal
     };
     (front_face, normal)
 }
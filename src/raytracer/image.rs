@@ -1,8 +1,9 @@
 use std::fs::File;
-use std::io::BufWriter;
+use std::io::{BufWriter, Write};
 use std::path::Path;
 
 use crate::raytracer::color::Color;
+use crate::raytracer::tone_mapping::ToneMapping;
 
 pub struct Image {
     pub data: Vec<Color>,
@@ -15,23 +16,87 @@ impl Image {
         &self.data[y * self.width + x]
     }
 
+    /// Output stage turning linear radiance into values for display
+    pub fn tone_mapped(&self, tone_mapping: &ToneMapping) -> Image {
+        Image {
+            data: self.data.iter().map(|c| tone_mapping.apply(c)).collect(),
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    /// 8-bit RGB triples, starting with the top row
+    pub fn to_rgb8(&self) -> Vec<u8> {
+        (0..self.height).map(|y| {
+            (0..self.width).map(|x| {
+                let c = self.get(x, self.height - y - 1).to_canvas_color();
+                vec![c.r, c.g, c.b]
+            }).collect::<Vec<Vec<u8>>>().concat()
+        }).collect::<Vec<Vec<u8>>>().concat()
+    }
+
     pub fn save_png(&self, path: &Path) {
         let file = File::create(path)
-            .expect(&format!("Unable to create file {}", path.display()));
+            .unwrap_or_else(|_| panic!("Unable to create file {}", path.display()));
         let mut encoder =
             png::Encoder::new(BufWriter::new(file),
                               self.width as u32, self.height as u32);
         encoder.set_color(png::ColorType::RGB);
         encoder.set_depth(png::BitDepth::Eight);
         let mut writer = encoder.write_header()
-            .expect(&format!("Unable to create file writer"));
-        let png_data = (0..self.height).map(|y| {
-            (0..self.width).map(|x| {
-                let c = self.get(x, self.height - y - 1).to_canvas_color();
-                vec![c.r, c.g, c.b]
-            }).collect::<Vec<Vec<u8>>>().concat()
-        }).collect::<Vec<Vec<u8>>>().concat();
-        writer.write_image_data(&png_data)
-            .expect(&format!("Unable to write file {}", path.display()));
+            .expect("Unable to create file writer");
+        writer.write_image_data(&self.to_rgb8())
+            .unwrap_or_else(|_| panic!("Unable to write file {}", path.display()));
+    }
+
+    /// Writes the values unclamped as a Portable Float Map, e.g., linear radiance for compositing.
+    pub fn save_pfm(&self, path: &Path) {
+        let file = File::create(path)
+            .unwrap_or_else(|_| panic!("Unable to create file {}", path.display()));
+        let mut writer = BufWriter::new(file);
+        // A negative scale means little-endian, and the rows go from bottom to top as here.
+        let header = format!("PF\n{} {}\n-1.0\n", self.width, self.height);
+        let data = self.data.iter()
+            .flat_map(|c| vec![c.r as f32, c.g as f32, c.b as f32])
+            .flat_map(|v| v.to_le_bytes().to_vec())
+            .collect::<Vec<u8>>();
+        writer.write_all(header.as_bytes())
+            .and_then(|_| writer.write_all(&data))
+            .and_then(|_| writer.flush())
+            .unwrap_or_else(|_| panic!("Unable to write file {}", path.display()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+
+    use crate::raytracer::environment_map::read_pfm;
+
+    use super::*;
+
+    #[test]
+    fn test_save_pfm_keeps_high_dynamic_range() {
+        let image = Image {
+            data: vec![
+                Color { r: 0.0, g: 0.25, b: 1.0 }, Color { r: 2.0, g: 40.0, b: 1e-4 },
+                Color { r: 0.5, g: 0.5, b: 0.5 }, Color { r: 1e3, g: 0.0, b: 3.0 },
+            ],
+            width: 2,
+            height: 2,
+        };
+        let path = std::env::temp_dir()
+            .join(format!("bouncing_spheres_test_save_pfm_{}.pfm", std::process::id()));
+        image.save_pfm(&path);
+        let (width, height, data) =
+            read_pfm(&mut BufReader::new(File::open(&path).unwrap())).unwrap();
+        assert_eq!((width, height), (2, 2));
+        // `read_pfm` returns the rows from top to bottom.
+        let expected = [&image.data[2..], &image.data[..2]].concat();
+        assert!(data.iter().zip(expected.iter()).all(|(a, b)| {
+            (a.r - b.r).abs() < 1e-6 * b.r.max(1.0) &&
+                (a.g - b.g).abs() < 1e-6 * b.g.max(1.0) &&
+                (a.b - b.b).abs() < 1e-6 * b.b.max(1.0)
+        }));
     }
 }
@@ -0,0 +1,191 @@
+use crate::raytracer::physics::{accelerate, move_positions};
+use crate::raytracer::sphere::Sphere;
+use crate::raytracer::vector3d::Vector3d;
+
+/// Accelerations of all spheres at their given positions
+pub type AccelerationField<'a> = dyn Fn(&[Sphere]) -> Vec<Vector3d> + 'a;
+
+/// Moves the spheres and changes their speeds according to an acceleration field.
+pub trait Integrator {
+    fn step(&self, spheres: &[Sphere], delta_t: f64, acceleration: &AccelerationField)
+            -> Vec<Sphere>;
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IntegratorKind {
+    ExplicitEuler,
+    SemiImplicitEuler,
+    VelocityVerlet,
+    Rk4,
+}
+
+impl IntegratorKind {
+    pub fn integrator(&self) -> &'static dyn Integrator {
+        match self {
+            IntegratorKind::ExplicitEuler => &ExplicitEuler,
+            IntegratorKind::SemiImplicitEuler => &SemiImplicitEuler,
+            IntegratorKind::VelocityVerlet => &VelocityVerlet,
+            IntegratorKind::Rk4 => &Rk4,
+        }
+    }
+}
+
+// Replaces positions and speeds, remembering the previous positions.
+fn with_state(spheres: &[Sphere], centers: &[Vector3d], speeds: &[Vector3d]) -> Vec<Sphere> {
+    spheres.iter().zip(centers.iter().zip(speeds.iter())).map(|(sphere, (center, speed))| {
+        Sphere {
+            center_old: sphere.center,
+            center: *center,
+            speed: *speed,
+            ..sphere.clone()
+        }
+    }).collect()
+}
+
+// `a + b * f` for every element
+fn add_scaled(a: &[Vector3d], b: &[Vector3d], f: f64) -> Vec<Vector3d> {
+    a.iter().zip(b.iter()).map(|(a, b)| *a + &(*b * f)).collect()
+}
+
+fn centers(spheres: &[Sphere]) -> Vec<Vector3d> {
+    spheres.iter().map(|sphere| sphere.center).collect()
+}
+
+fn speeds(spheres: &[Sphere]) -> Vec<Vector3d> {
+    spheres.iter().map(|sphere| sphere.speed).collect()
+}
+
+/// Moves with the old speeds, then accelerates at the new positions.
+/// This is the original behavior of `World::advance`.
+pub struct ExplicitEuler;
+
+impl Integrator for ExplicitEuler {
+    fn step(&self, spheres: &[Sphere], delta_t: f64, acceleration: &AccelerationField)
+            -> Vec<Sphere> {
+        let moved = move_positions(spheres, delta_t);
+        accelerate(&moved, &acceleration(&moved), delta_t)
+    }
+}
+
+/// Accelerates at the old positions, then moves with the new speeds (symplectic).
+pub struct SemiImplicitEuler;
+
+impl Integrator for SemiImplicitEuler {
+    fn step(&self, spheres: &[Sphere], delta_t: f64, acceleration: &AccelerationField)
+            -> Vec<Sphere> {
+        move_positions(&accelerate(spheres, &acceleration(spheres), delta_t), delta_t)
+    }
+}
+
+/// Second order and symplectic, i.e., orbits do not gain or lose energy over time.
+pub struct VelocityVerlet;
+
+impl Integrator for VelocityVerlet {
+    fn step(&self, spheres: &[Sphere], delta_t: f64, acceleration: &AccelerationField)
+            -> Vec<Sphere> {
+        let accelerations = acceleration(spheres);
+        let new_centers = add_scaled(
+            &add_scaled(&centers(spheres), &speeds(spheres), delta_t),
+            &accelerations, 0.5 * delta_t * delta_t);
+        let moved = with_state(spheres, &new_centers, &speeds(spheres));
+        let new_accelerations = acceleration(&moved);
+        let new_speeds = add_scaled(
+            &add_scaled(&speeds(spheres), &accelerations, 0.5 * delta_t),
+            &new_accelerations, 0.5 * delta_t);
+        with_state(spheres, &new_centers, &new_speeds)
+    }
+}
+
+/// Classic fourth order Runge-Kutta, most accurate, but four gravity evaluations per step
+pub struct Rk4;
+
+impl Integrator for Rk4 {
+    fn step(&self, spheres: &[Sphere], delta_t: f64, acceleration: &AccelerationField)
+            -> Vec<Sphere> {
+        let x = centers(spheres);
+        let v = speeds(spheres);
+        let k1_v = acceleration(spheres);
+        let x2 = add_scaled(&x, &v, 0.5 * delta_t);
+        let v2 = add_scaled(&v, &k1_v, 0.5 * delta_t);
+        let k2_v = acceleration(&with_state(spheres, &x2, &v2));
+        let x3 = add_scaled(&x, &v2, 0.5 * delta_t);
+        let v3 = add_scaled(&v, &k2_v, 0.5 * delta_t);
+        let k3_v = acceleration(&with_state(spheres, &x3, &v3));
+        let x4 = add_scaled(&x, &v3, delta_t);
+        let v4 = add_scaled(&v, &k3_v, delta_t);
+        let k4_v = acceleration(&with_state(spheres, &x4, &v4));
+        let weighted_sum = |k1: &[Vector3d], k2: &[Vector3d], k3: &[Vector3d], k4: &[Vector3d]| {
+            add_scaled(&add_scaled(&add_scaled(k1, k2, 2.0), k3, 2.0), k4, 1.0)
+        };
+        let new_centers = add_scaled(&x, &weighted_sum(&v, &v2, &v3, &v4), delta_t / 6.0);
+        let new_speeds = add_scaled(&v, &weighted_sum(&k1_v, &k2_v, &k3_v, &k4_v), delta_t / 6.0);
+        with_state(spheres, &new_centers, &new_speeds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::raytracer::color::Color;
+    use crate::raytracer::material::Material;
+    use crate::raytracer::physics::gravity_accelerations;
+    use crate::raytracer::quaternion::Quaternion;
+    use crate::raytracer::texture::Texture;
+
+    use super::*;
+
+    fn sphere(center: Vector3d, mass: f64, speed: Vector3d) -> Sphere {
+        Sphere {
+            center,
+            radius: 0.1,
+            material: Material {
+                albedo: Texture::Solid(Color::black()),
+                reflectiveness: 0.0,
+                reflection_fuzz: 0.0,
+                transparency: 0.0,
+                refraction_index: 1.5,
+                absorption: Color::black(),
+                emission: Color::black(),
+                flash_emission: 0.0,
+            },
+            speed,
+            mass,
+            extra_brightness: 0.0,
+            center_old: center,
+            angular_velocity: Vector3d::null(),
+            orientation: Quaternion::identity(),
+            orientation_old: Quaternion::identity(),
+        }
+    }
+
+    // Largest relative deviation from a circular orbit over one revolution
+    fn orbit_error(integrator: IntegratorKind, steps: usize) -> f64 {
+        let (mass, radius): (f64, f64) = (1000.0, 10.0);
+        let speed = (mass / radius).sqrt();
+        let period = 2.0 * std::f64::consts::PI * radius / speed;
+        let acceleration = |spheres: &[Sphere]| gravity_accelerations(spheres, 1.0);
+        let mut spheres = vec![
+            sphere(Vector3d::null(), mass, Vector3d::null()),
+            sphere(Vector3d { x: radius, y: 0.0, z: 0.0 }, 1e-9,
+                   Vector3d { x: 0.0, y: 0.0, z: speed }),
+        ];
+        let mut max_error: f64 = 0.0;
+        for _ in 0..steps {
+            spheres = integrator.integrator().step(&spheres, period / steps as f64, &acceleration);
+            let distance = (spheres[1].center - &spheres[0].center).length();
+            max_error = max_error.max((distance - radius).abs() / radius);
+        }
+        max_error
+    }
+
+    #[test]
+    fn test_integrators_keep_orbit() {
+        let explicit_euler = orbit_error(IntegratorKind::ExplicitEuler, 50);
+        let semi_implicit_euler = orbit_error(IntegratorKind::SemiImplicitEuler, 50);
+        let velocity_verlet = orbit_error(IntegratorKind::VelocityVerlet, 50);
+        let rk4 = orbit_error(IntegratorKind::Rk4, 50);
+        assert!(explicit_euler < 0.1 && semi_implicit_euler < 0.1);
+        assert!(velocity_verlet < 0.01);
+        assert!(rk4 < 0.0001);
+    }
+}
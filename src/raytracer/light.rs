@@ -0,0 +1,173 @@
+use std::f64::consts::PI;
+
+use rand::prelude::StdRng;
+use rand::Rng;
+
+use crate::raytracer::color::Color;
+use crate::raytracer::environment_map::{layers_pdf, layers_radiance, sample_layers};
+use crate::raytracer::hit::Hit;
+use crate::raytracer::ray::Ray;
+use crate::raytracer::render::Sky;
+use crate::raytracer::sphere::Sphere;
+use crate::raytracer::vector3d::{dot, random_in_cone, unit_vector, Vector3d};
+use crate::raytracer::world::World;
+
+/// Directional light source, visible as a disk in the sky
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct Sun {
+    // Points towards the sun
+    pub direction: Vector3d,
+    // Irradiance on a surface facing the sun
+    pub color: Color,
+    // In degrees
+    #[serde(default = "default_sun_angular_radius")]
+    pub angular_radius: f64,
+}
+
+pub fn default_sun_angular_radius() -> f64 {
+    0.27
+}
+
+#[inline(always)]
+fn cone_pdf(cos_theta_max: f64) -> f64 {
+    1.0 / (2.0 * PI * (1.0 - cos_theta_max))
+}
+
+impl Sun {
+    fn cos_theta_max(&self) -> f64 {
+        self.angular_radius.to_radians().cos()
+    }
+
+    pub fn radiance(&self) -> Color {
+        self.color * cone_pdf(self.cos_theta_max())
+    }
+
+    pub fn is_in_direction(&self, direction: &Vector3d) -> bool {
+        dot(&unit_vector(direction), &unit_vector(&self.direction)) >= self.cos_theta_max()
+    }
+}
+
+// Balances two sampling strategies in multiple importance sampling.
+#[inline(always)]
+pub fn power_heuristic(pdf: f64, other_pdf: f64) -> f64 {
+    let pdf_squared = pdf * pdf;
+    pdf_squared / (pdf_squared + other_pdf * other_pdf)
+}
+
+// Direction to the sphere and the cosine of the cone it covers,
+// or `None` if `from` is inside of the sphere.
+#[inline(always)]
+fn sphere_cone(sphere: &Sphere, from: &Vector3d, frame_time: f64) -> Option<(Vector3d, f64)> {
+    let to_center = sphere.center_at_frame_time(frame_time) - from;
+    let dist_squared = to_center.length_squared();
+    let radius_squared = sphere.radius * sphere.radius;
+    if dist_squared <= radius_squared {
+        return None;
+    }
+    Some((unit_vector(&to_center), (1.0 - radius_squared / dist_squared).sqrt()))
+}
+
+/// Something `sample_direct_light` can sample
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Light {
+    Sphere(usize),
+    Sun,
+    Environment,
+}
+
+fn has_environment(sky: &Sky) -> bool {
+    !sky.environment_layers().is_empty()
+}
+
+fn num_lights(world: &World, sky: &Sky) -> usize {
+    world.emitters().len() +
+        if sky.sun.is_some() { 1 } else { 0 } +
+        if has_environment(sky) { 1 } else { 0 }
+}
+
+fn light_by_index(world: &World, sky: &Sky, i: usize) -> Light {
+    let emitters = world.emitters();
+    if i < emitters.len() {
+        Light::Sphere(emitters[i])
+    } else if i == emitters.len() && sky.sun.is_some() {
+        Light::Sun
+    } else {
+        Light::Environment
+    }
+}
+
+/// Probability density of `sample_direct_light` choosing the given direction
+/// from `from` towards the given light.
+pub fn light_pdf(world: &World, sky: &Sky, from: &Vector3d, direction: &Vector3d,
+                 frame_time: f64, light: Light) -> f64 {
+    let pdf = match light {
+        Light::Sphere(i) => {
+            if !world.emitters().contains(&i) {
+                return 0.0;
+            }
+            match sphere_cone(&world.spheres()[i], from, frame_time) {
+                Some((_, cos_theta_max)) => cone_pdf(cos_theta_max),
+                None => 0.0,
+            }
+        }
+        Light::Sun => match &sky.sun {
+            Some(sun) if sun.is_in_direction(direction) => cone_pdf(sun.cos_theta_max()),
+            _ => 0.0,
+        }
+        Light::Environment => layers_pdf(sky.environment_layers(), direction),
+    };
+    pdf / num_lights(world, sky) as f64
+}
+
+/// Light arriving directly from one randomly chosen light source
+/// at a Lambertian surface, weighted for multiple importance sampling.
+pub fn sample_direct_light(rng: &mut StdRng, world: &World, sky: &Sky,
+                           rec: &Hit, albedo: &Color, frame_time: f64) -> Color {
+    let num_lights = num_lights(world, sky);
+    if num_lights == 0 {
+        return Color::black();
+    }
+    let light = light_by_index(world, sky, rng.gen_range(0, num_lights));
+    let (direction, direction_pdf) = match light {
+        Light::Sphere(i) => {
+            if rec.sphere_index == Some(i) {
+                return Color::black();
+            }
+            match sphere_cone(&world.spheres()[i], &rec.position, frame_time) {
+                Some((axis, cos_theta_max)) =>
+                    (random_in_cone(rng, &axis, cos_theta_max), cone_pdf(cos_theta_max)),
+                None => return Color::black(),
+            }
+        }
+        Light::Sun => {
+            let sun = sky.sun.as_ref().unwrap();
+            (random_in_cone(rng, &unit_vector(&sun.direction), sun.cos_theta_max()),
+             cone_pdf(sun.cos_theta_max()))
+        }
+        Light::Environment => {
+            let layers = sky.environment_layers();
+            match sample_layers(rng, layers) {
+                Some(direction) => (direction, layers_pdf(layers, &direction)),
+                None => return Color::black(),
+            }
+        }
+    };
+    let cosine = dot(&rec.normal, &direction);
+    if cosine <= 0.0 || direction_pdf <= 0.0 {
+        return Color::black();
+    }
+    let shadow_ray = Ray {
+        origin: rec.position,
+        direction,
+        frame_time,
+    };
+    let radiance = match (world.hit(&shadow_ray, 0.001, 9999999999.9), light) {
+        (Some(hit), Light::Sphere(i)) if hit.sphere_index == Some(i) => hit.material.emission,
+        (None, Light::Sun) => sky.sun.as_ref().unwrap().radiance(),
+        (None, Light::Environment) => layers_radiance(sky.environment_layers(), &direction),
+        _ => return Color::black(),
+    };
+    let light_pdf = direction_pdf / num_lights as f64;
+    let bsdf_pdf = cosine / PI;
+    *albedo * &radiance * (bsdf_pdf * power_heuristic(light_pdf, bsdf_pdf) / light_pdf)
+}
@@ -4,29 +4,62 @@ use rand::Rng;
 use crate::raytracer::color::Color;
 use crate::raytracer::hit::Hit;
 use crate::raytracer::ray::Ray;
-use crate::raytracer::vector3d::{dot, random_unit_vector, reflect, unit_vector};
+use crate::raytracer::texture::Texture;
+use crate::raytracer::vector3d::{dot, random_unit_vector, reflect, refract, unit_vector};
 
-#[derive(Copy, Clone)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Material {
-    pub albedo: Color,
+    pub albedo: Texture,
     pub reflectiveness: f64,
     pub reflection_fuzz: f64,
+    // Probability of a ray being refracted or reflected like by glass
+    #[serde(default)]
+    pub transparency: f64,
+    #[serde(default = "default_refraction_index")]
+    pub refraction_index: f64,
+    // Per unit of distance travelled inside the sphere (Beer's law)
+    #[serde(default)]
+    pub absorption: Color,
+    // Light emitted by the surface itself
+    #[serde(default)]
+    pub emission: Color,
+    // If positive, collision flashes (`Sphere::extra_brightness`) emit light
+    // with this strength instead of just brightening the albedo.
+    #[serde(default)]
+    pub flash_emission: f64,
+}
+
+pub struct Scattering {
+    pub ray: Ray,
+    pub attenuation: Color,
+    // Lambertian bounces are combined with direct light sampling.
+    pub is_diffuse: bool,
+}
+
+pub fn default_refraction_index() -> f64 {
+    1.5
+}
+
+// Schlick's approximation of the Fresnel reflectance
+fn reflectance(cosine: f64, refraction_ratio: f64) -> f64 {
+    let r0 = ((1.0 - refraction_ratio) / (1.0 + refraction_ratio)).powf(2.0);
+    r0 + (1.0 - r0) * (1.0 - cosine).powf(5.0)
 }
 
 impl Material {
     #[inline(always)]
-    fn scatter_lambertian(&self, rng: &mut StdRng, ray: &Ray, rec: &Hit) -> Option<(Ray, Color)> {
+    fn scatter_lambertian(&self, rng: &mut StdRng, ray: &Ray, rec: &Hit) -> Option<Scattering> {
         let scatter_direction = rec.normal + &random_unit_vector(rng);
         let scattered = Ray {
             origin: rec.position,
             direction: scatter_direction,
             frame_time: ray.frame_time,
         };
-        Some((scattered, self.albedo))
+        Some(Scattering { ray: scattered, attenuation: rec.albedo, is_diffuse: true })
     }
 
     #[inline(always)]
-    fn scatter_reflective(&self, rng: &mut StdRng, ray: &Ray, rec: &Hit) -> Option<(Ray, Color)> {
+    fn scatter_reflective(&self, rng: &mut StdRng, ray: &Ray, rec: &Hit) -> Option<Scattering> {
         let reflected = reflect(&unit_vector(&ray.direction), &rec.normal);
         let scattered = Ray {
             origin: rec.position,
@@ -34,18 +67,147 @@ impl Material {
             frame_time: ray.frame_time,
         };
         if dot(&scattered.direction, &rec.normal) > 0.0 {
-            Some((scattered, self.albedo))
+            Some(Scattering { ray: scattered, attenuation: rec.albedo, is_diffuse: false })
         } else {
             None
         }
     }
 
     #[inline(always)]
-    pub fn scatter(&self, rng: &mut StdRng, ray: &Ray, rec: &Hit) -> Option<(Ray, Color)> {
-        if rng.gen::<f64>() > self.reflectiveness {
+    fn scatter_dielectric(&self, rng: &mut StdRng, ray: &Ray, rec: &Hit) -> Option<Scattering> {
+        let refraction_ratio = if rec.front_face {
+            1.0 / self.refraction_index
+        } else {
+            self.refraction_index
+        };
+        let unit_direction = unit_vector(&ray.direction);
+        let cos_theta = (-dot(&unit_direction, &rec.normal)).min(1.0);
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        let cannot_refract = refraction_ratio * sin_theta > 1.0;
+        let direction = if cannot_refract ||
+            reflectance(cos_theta, refraction_ratio) > rng.gen::<f64>() {
+            reflect(&unit_direction, &rec.normal)
+        } else {
+            refract(&unit_direction, &rec.normal, refraction_ratio)
+        };
+        let attenuation = if rec.front_face {
+            Color::white()
+        } else {
+            let distance = rec.t * ray.direction.length();
+            Color {
+                r: (-self.absorption.r * distance).exp(),
+                g: (-self.absorption.g * distance).exp(),
+                b: (-self.absorption.b * distance).exp(),
+            }
+        };
+        let scattered = Ray {
+            origin: rec.position,
+            direction,
+            frame_time: ray.frame_time,
+        };
+        Some(Scattering { ray: scattered, attenuation, is_diffuse: false })
+    }
+
+    #[inline(always)]
+    pub fn scatter(&self, rng: &mut StdRng, ray: &Ray, rec: &Hit) -> Option<Scattering> {
+        if self.transparency > 0.0 && rng.gen::<f64>() < self.transparency {
+            self.scatter_dielectric(rng, ray, rec)
+        } else if rng.gen::<f64>() > self.reflectiveness {
             self.scatter_lambertian(rng, ray, rec)
         } else {
             self.scatter_reflective(rng, ray, rec)
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use crate::raytracer::vector3d::Vector3d;
+
+    use super::*;
+
+    fn glass(absorption: Color) -> Material {
+        Material {
+            albedo: Texture::Solid(Color::white()),
+            reflectiveness: 0.0,
+            reflection_fuzz: 0.0,
+            transparency: 1.0,
+            refraction_index: 1.5,
+            absorption,
+            emission: Color::black(),
+            flash_emission: 0.0,
+        }
+    }
+
+    // Surface at the origin with its outward normal pointing up
+    fn hit(ray: &Ray, t: f64, material: Material) -> Hit {
+        let front_face = ray.direction.y < 0.0;
+        Hit {
+            position: Vector3d::null(),
+            t,
+            normal: Vector3d { x: 0.0, y: if front_face { 1.0 } else { -1.0 }, z: 0.0 },
+            front_face,
+            material,
+            u: 0.0,
+            v: 0.0,
+            albedo: Color::white(),
+            sphere_index: None,
+        }
+    }
+
+    fn ray(direction: Vector3d) -> Ray {
+        Ray { origin: Vector3d::null() - &direction, direction, frame_time: 0.0 }
+    }
+
+    #[test]
+    fn test_total_internal_reflection() {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        // Leaving the glass at 60 degrees, i.e., beyond the critical angle of 41.8 degrees.
+        let (sin_theta, cos_theta) = 60.0_f64.to_radians().sin_cos();
+        let ray = ray(Vector3d { x: sin_theta, y: cos_theta, z: 0.0 });
+        let rec = hit(&ray, 1.0, glass(Color::black()));
+        assert!(sin_theta * rec.material.refraction_index > 1.0);
+        let reflected = Vector3d { x: sin_theta, y: -cos_theta, z: 0.0 };
+        assert!((0..1000).all(|_| {
+            let scattering = rec.material.scatter_dielectric(&mut rng, &ray, &rec).unwrap();
+            (scattering.ray.direction - &reflected).length() < 1e-9
+        }));
+    }
+
+    #[test]
+    fn test_schlick_reflectance() {
+        let ratio = 1.0 / 1.5;
+        // (n1 - n2)^2 / (n1 + n2)^2 at normal incidence, everything at grazing incidence
+        assert!((reflectance(1.0, ratio) - 0.04).abs() < 1e-12);
+        assert!((reflectance(0.0, ratio) - 1.0).abs() < 1e-12);
+
+        let mut rng: StdRng = SeedableRng::seed_from_u64(1);
+        let ray = ray(Vector3d { x: 0.0, y: -1.0, z: 0.0 });
+        let rec = hit(&ray, 1.0, glass(Color::black()));
+        let num_samples = 100000;
+        let reflected = (0..num_samples).filter(|_| {
+            rec.material.scatter_dielectric(&mut rng, &ray, &rec).unwrap().ray.direction.y > 0.0
+        }).count();
+        assert!((reflected as f64 / num_samples as f64 - 0.04).abs() < 0.003);
+    }
+
+    #[test]
+    fn test_beers_law_absorption() {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(2);
+        let absorption = Color { r: 0.1, g: 0.5, b: 1.0 };
+        let attenuation = |ray: &Ray, t: f64, rng: &mut StdRng| {
+            let rec = hit(ray, t, glass(absorption));
+            rec.material.scatter_dielectric(rng, ray, &rec).unwrap().attenuation
+        };
+        // Leaving the glass after a distance of 2, with a direction of length 2
+        let leaving = attenuation(&ray(Vector3d { x: 0.0, y: 2.0, z: 0.0 }), 1.0, &mut rng);
+        assert!((leaving.r - (-absorption.r * 2.0).exp()).abs() < 1e-12);
+        assert!((leaving.g - (-absorption.g * 2.0).exp()).abs() < 1e-12);
+        assert!((leaving.b - (-absorption.b * 2.0).exp()).abs() < 1e-12);
+        // Nothing is absorbed outside of the glass.
+        let entering = attenuation(&ray(Vector3d { x: 0.0, y: -2.0, z: 0.0 }), 1.0, &mut rng);
+        assert_eq!(entering, Color::white());
+    }
+}
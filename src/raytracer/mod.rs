@@ -1,11 +1,21 @@
+pub mod barnes_hut;
+pub mod broad_phase;
+pub mod bvh;
 pub mod camera;
 pub mod color;
+pub mod environment_map;
 pub mod hit;
 pub mod image;
+pub mod integrator;
+pub mod light;
 pub mod material;
+pub mod physical_sky;
 pub mod physics;
+pub mod quaternion;
 pub mod ray;
 pub mod render;
 pub mod sphere;
+pub mod texture;
+pub mod tone_mapping;
 pub mod vector3d;
-pub mod world;
\ No newline at end of file
+pub mod world;
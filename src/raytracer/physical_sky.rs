@@ -0,0 +1,152 @@
+use crate::raytracer::color::Color;
+use crate::raytracer::vector3d::{dot, unit_vector, Vector3d};
+
+// Coefficients (A to E) of the Perez sky luminance distribution, linear in turbidity
+type PerezCoefficients = [f64; 5];
+
+fn perez_coefficients(turbidity: f64, rows: &[(f64, f64); 5]) -> PerezCoefficients {
+    let mut coefficients = [0.0; 5];
+    for (c, (factor, offset)) in coefficients.iter_mut().zip(rows.iter()) {
+        *c = factor * turbidity + offset;
+    }
+    coefficients
+}
+
+fn perez(c: &PerezCoefficients, cos_theta: f64, gamma: f64) -> f64 {
+    let cos_gamma = gamma.cos();
+    (1.0 + c[0] * (c[1] / cos_theta.max(0.001)).exp()) *
+        (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * cos_gamma * cos_gamma)
+}
+
+fn polynomial(coefficients: &[f64; 4], x: f64) -> f64 {
+    coefficients.iter().fold(0.0, |acc, c| acc * x + c)
+}
+
+/// Analytic daylight model by Preetham, Shirley and Smits (1999)
+pub struct PhysicalSky {
+    // Points towards the sun
+    sun_direction: Vector3d,
+    // Haziness of the atmosphere, 2 (clear) to 10 (hazy)
+    turbidity: f64,
+    // Scales the luminance, which the model yields in kcd/m²
+    intensity: f64,
+    coefficients_luminance: PerezCoefficients,
+    coefficients_x: PerezCoefficients,
+    coefficients_y: PerezCoefficients,
+    // Luminance and chromaticity in the zenith
+    zenith: (f64, f64, f64),
+}
+
+impl PhysicalSky {
+    pub fn new(sun_direction: &Vector3d, turbidity: f64, intensity: f64) -> PhysicalSky {
+        let sun_direction = unit_vector(sun_direction);
+        // The model is only valid for the sun above the horizon.
+        let theta_sun = sun_direction.y.clamp(0.0, 1.0).acos();
+        let t = turbidity;
+        let chi = (4.0 / 9.0 - t / 120.0) * (std::f64::consts::PI - 2.0 * theta_sun);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let zenith_x =
+            t * t * polynomial(&[0.00166, -0.00375, 0.00209, 0.0], theta_sun) +
+                t * polynomial(&[-0.02903, 0.06377, -0.03202, 0.00394], theta_sun) +
+                polynomial(&[0.11693, -0.21196, 0.06052, 0.25886], theta_sun);
+        let zenith_y =
+            t * t * polynomial(&[0.00275, -0.00610, 0.00317, 0.0], theta_sun) +
+                t * polynomial(&[-0.04214, 0.08970, -0.04153, 0.00516], theta_sun) +
+                polynomial(&[0.15346, -0.26756, 0.06670, 0.26688], theta_sun);
+        let coefficients_luminance = perez_coefficients(t, &[
+            (0.1787, -1.4630), (-0.3554, 0.4275), (-0.0227, 5.3251),
+            (0.1206, -2.5771), (-0.0670, 0.3703)]);
+        let coefficients_x = perez_coefficients(t, &[
+            (-0.0193, -0.2592), (-0.0665, 0.0008), (-0.0004, 0.2125),
+            (-0.0641, -0.8989), (-0.0033, 0.0452)]);
+        let coefficients_y = perez_coefficients(t, &[
+            (-0.0167, -0.2608), (-0.0950, 0.0092), (-0.0079, 0.2102),
+            (-0.0441, -1.6537), (-0.0109, 0.0529)]);
+        let zenith_values = |c: &PerezCoefficients, value: f64| {
+            value / perez(c, 1.0, theta_sun)
+        };
+        PhysicalSky {
+            sun_direction,
+            turbidity,
+            intensity,
+            zenith: (
+                zenith_values(&coefficients_luminance, zenith_luminance),
+                zenith_values(&coefficients_x, zenith_x),
+                zenith_values(&coefficients_y, zenith_y),
+            ),
+            coefficients_luminance,
+            coefficients_x,
+            coefficients_y,
+        }
+    }
+
+    pub fn radiance(&self, direction: &Vector3d) -> Color {
+        let direction = unit_vector(direction);
+        // Below the horizon, the sky looks like at the horizon.
+        let cos_theta = direction.y.max(0.0);
+        let gamma = dot(&direction, &self.sun_direction).clamp(-1.0, 1.0).acos();
+        let luminance = self.zenith.0 * perez(&self.coefficients_luminance, cos_theta, gamma);
+        let x = self.zenith.1 * perez(&self.coefficients_x, cos_theta, gamma);
+        let y = self.zenith.2 * perez(&self.coefficients_y, cos_theta, gamma);
+        xyy_to_rgb(x, y, luminance) * self.intensity
+    }
+
+    /// Fraction of the sunlight reaching the ground, per color channel
+    pub fn sun_transmittance(&self) -> Color {
+        let zenith_angle = self.sun_direction.y.clamp(0.0, 1.0).acos();
+        // Relative optical air mass (Kasten and Young)
+        let air_mass = 1.0 / (zenith_angle.cos() +
+            0.15 * (93.885 - zenith_angle.to_degrees()).powf(-1.253));
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        let transmittance = |wavelength_micrometers: f64| {
+            let rayleigh = 0.008735 * wavelength_micrometers.powf(-4.08);
+            let aerosols = beta * wavelength_micrometers.powf(-1.3);
+            (-air_mass * (rayleigh + aerosols)).exp()
+        };
+        Color {
+            r: transmittance(0.65),
+            g: transmittance(0.57),
+            b: transmittance(0.475),
+        }
+    }
+}
+
+fn xyy_to_rgb(x: f64, y: f64, luminance: f64) -> Color {
+    if y <= 0.0 {
+        return Color::black();
+    }
+    let big_x = x / y * luminance;
+    let big_z = (1.0 - x - y) / y * luminance;
+    Color {
+        r: (3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z).max(0.0),
+        g: (-0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z).max(0.0),
+        b: (0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z).max(0.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sun_direction(elevation_degrees: f64) -> Vector3d {
+        let elevation = elevation_degrees.to_radians();
+        Vector3d { x: elevation.cos(), y: elevation.sin(), z: 0.0 }
+    }
+
+    #[test]
+    fn test_sky_is_brighter_around_the_sun() {
+        let sky = PhysicalSky::new(&sun_direction(30.0), 3.0, 1.0);
+        let towards_sun = sky.radiance(&sun_direction(35.0));
+        let away_from_sun = sky.radiance(&Vector3d { x: -1.0, y: 0.6, z: 0.0 });
+        assert!(towards_sun.g > 2.0 * away_from_sun.g);
+        assert!(away_from_sun.b > away_from_sun.r);
+    }
+
+    #[test]
+    fn test_sunset_is_red() {
+        let noon = PhysicalSky::new(&sun_direction(70.0), 3.0, 1.0).sun_transmittance();
+        let sunset = PhysicalSky::new(&sun_direction(2.0), 3.0, 1.0).sun_transmittance();
+        assert!(sunset.r > 2.0 * sunset.b);
+        assert!(noon.r > sunset.r && noon.b > sunset.b);
+    }
+}
@@ -1,13 +1,27 @@
-use std::cell::RefCell;
-use std::ops::Deref;
-
-use itertools::Itertools;
-
+use crate::raytracer::broad_phase::candidate_pairs;
+use crate::raytracer::integrator::IntegratorKind;
 use crate::raytracer::sphere::Sphere;
-use crate::raytracer::vector3d::{dot, unit_vector, Vector3d, zero_in};
+use crate::raytracer::quaternion::Quaternion;
+use crate::raytracer::vector3d::{blend_vectors, cross, dot, unit_vector, Vector3d, zero_in};
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum GravityApproximation {
+    // Sum of the pulls of all pairs of spheres, O(n^2)
+    Exact,
+    // Octree-based, O(n log n). Larger opening angles are faster but less accurate.
+    BarnesHut {
+        #[serde(rename = "theta")]
+        opening_angle: f64,
+    },
+}
 
 pub struct PhysicsSettings {
+    pub integrator: IntegratorKind,
+    // Fixed number of steps per call of `World::advance`
+    pub substeps: usize,
     pub gravity_constant: f64,
+    pub gravity_approximation: GravityApproximation,
     pub bounciness: f64,
 
     // Avoid infinite bouncing
@@ -17,138 +31,460 @@ pub struct PhysicsSettings {
     pub dim_factor: f64,
     pub dim_constant: f64,
     pub friction: f64,
+    // Coulomb friction coefficient at contacts, transfers sliding into spin
+    pub contact_friction: f64,
+    pub rolling_resistance: f64,
+    // Detect collisions between the frames of fast spheres.
+    pub continuous_collision_detection: bool,
 }
 
-pub fn gravitate(spheres: &Vec<Sphere>, delta_t: f64, gravity_constant: f64) -> Vec<Sphere> {
+/// Gravitational pull on every sphere by all others
+pub fn gravity_accelerations(spheres: &[Sphere], gravity_constant: f64) -> Vec<Vector3d> {
     spheres
         .iter()
         .map(|sphere| {
-            let acceleration = spheres
+            spheres
                 .iter()
                 .map(|other| {
-                    if other as *const _ == sphere as *const _ {
+                    if std::ptr::eq(other, sphere) {
                         Vector3d::null()
                     } else {
                         let diff = other.center - &sphere.center;
                         let dist = diff.length();
-                        unit_vector(&diff) * delta_t * gravity_constant * other.mass / dist.powf(2.0)
+                        unit_vector(&diff) * gravity_constant * other.mass / dist.powf(2.0)
                     }
                 }).fold(Vector3d::null(),
-                        |a: Vector3d, b: Vector3d| a + &b);
-            Sphere {
-                speed: sphere.speed + &acceleration,
-                ..*sphere
-            }
+                        |a: Vector3d, b: Vector3d| a + &b)
         }).collect()
 }
 
-pub fn bounce(spheres: &Vec<Sphere>, bounciness: f64, flash_strength: f64,
-              bounce_round_to_zero_threshold: f64) -> Vec<Sphere> {
-    let mut spheres_copy = spheres.to_vec();
-    let new_spheres = spheres_copy.iter_mut()
-        .map(|s| RefCell::new(s))
-        .collect::<Vec<RefCell<&mut Sphere>>>();
-    new_spheres.iter().combinations(2).for_each(|pair| {
-        if let [a, b] = pair.as_slice() {
-            let mut a = a.borrow_mut();
-            let mut b = b.borrow_mut();
-            let diff = b.center - &a.center;
-            let dist = diff.length();
-            let min_dist = a.radius + b.radius;
-            if dist < min_dist {
-                let dir_a_to_b = unit_vector(&(b.center - &a.center));
-                let v_a_c_length = dot(&a.speed, &dir_a_to_b);
-                let v_b_c_length = dot(&b.speed, &dir_a_to_b);
-                let v_a_c = dir_a_to_b * v_a_c_length;
-                let v_b_c = dir_a_to_b * v_b_c_length;
-                let v_a_c_prime_length = (a.mass * v_a_c_length + b.mass * v_b_c_length - b.mass * (v_a_c_length - v_b_c_length) * bounciness) / (a.mass + b.mass);
-                let v_b_c_prime_length = (b.mass * v_b_c_length + a.mass * v_a_c_length - a.mass * (v_b_c_length - v_a_c_length) * bounciness) / (a.mass + b.mass);
-                let v_a_c_prime = dir_a_to_b * zero_in(bounce_round_to_zero_threshold, v_a_c_prime_length);
-                let v_b_c_prime = dir_a_to_b * zero_in(bounce_round_to_zero_threshold, v_b_c_prime_length);
-                let new_speed_a = a.speed - &v_a_c + &v_a_c_prime;
-                let new_speed_b = b.speed - &v_b_c + &v_b_c_prime;
-                let acceleration_a = (a.speed - &new_speed_a).length();
-                let acceleration_b = (b.speed - &new_speed_b).length();
-                if acceleration_a > 10.0 {
-                    a.extra_brightness = (acceleration_a * flash_strength).max(a.extra_brightness);
-                }
-                if acceleration_b > 10.0 {
-                    b.extra_brightness = (acceleration_b * flash_strength).max(b.extra_brightness);
-                }
-                a.speed = new_speed_a;
-                b.speed = new_speed_b;
-            }
+pub fn accelerate(spheres: &[Sphere], accelerations: &[Vector3d], delta_t: f64) -> Vec<Sphere> {
+    spheres.iter().zip(accelerations.iter()).map(|(sphere, acceleration)| {
+        Sphere {
+            speed: sphere.speed + &(*acceleration * delta_t),
+            ..sphere.clone()
         }
-    });
-    new_spheres.iter().map(|s| {
-        s.borrow().deref().deref().clone()
     }).collect()
 }
 
-pub fn solve_non_overlapping_constraint(spheres: &Vec<Sphere>) -> Vec<Sphere> {
+pub fn gravitate(spheres: &[Sphere], delta_t: f64, gravity_constant: f64) -> Vec<Sphere> {
+    accelerate(spheres, &gravity_accelerations(spheres, gravity_constant), delta_t)
+}
+
+fn pair_mut(spheres: &mut [Sphere], i: usize, j: usize) -> (&mut Sphere, &mut Sphere) {
+    let (left, right) = spheres.split_at_mut(j);
+    (&mut left[i], &mut right[0])
+}
+
+pub fn bounce(spheres: &[Sphere], bounciness: f64, flash_strength: f64,
+              bounce_round_to_zero_threshold: f64, contact_friction: f64) -> Vec<Sphere> {
+    bounce_pairs(spheres, &candidate_pairs(spheres), bounciness, flash_strength,
+                 bounce_round_to_zero_threshold, contact_friction)
+}
+
+// Tangential impulse on `a` at the contact with `b`,
+// limited by the normal impulse and by what is needed to stop the sliding.
+fn friction_impulse(a: &Sphere, b: &Sphere, dir_a_to_b: &Vector3d,
+                    normal_impulse: f64, contact_friction: f64) -> Vector3d {
+    let contact_speed_a = a.speed + &cross(&a.angular_velocity, &(*dir_a_to_b * a.radius));
+    let contact_speed_b = b.speed + &cross(&b.angular_velocity, &(*dir_a_to_b * -b.radius));
+    let relative_speed = contact_speed_b - &contact_speed_a;
+    let sliding = relative_speed - &(*dir_a_to_b * dot(&relative_speed, dir_a_to_b));
+    let sliding_speed = sliding.length();
+    if sliding_speed < 1e-12 {
+        return Vector3d::null();
+    }
+    let inverse_effective_mass = 1.0 / a.mass + 1.0 / b.mass +
+        a.radius * a.radius / a.moment_of_inertia() + b.radius * b.radius / b.moment_of_inertia();
+    let impulse = (sliding_speed / inverse_effective_mass).min(contact_friction * normal_impulse);
+    sliding * (impulse / sliding_speed)
+}
+
+// Exchanges momentum along the line between the centers of two touching spheres.
+fn bounce_pair(a: &mut Sphere, b: &mut Sphere, bounciness: f64, flash_strength: f64,
+               bounce_round_to_zero_threshold: f64, contact_friction: f64) {
+    let dir_a_to_b = unit_vector(&(b.center - &a.center));
+    let v_a_c_length = dot(&a.speed, &dir_a_to_b);
+    let v_b_c_length = dot(&b.speed, &dir_a_to_b);
+    let v_a_c = dir_a_to_b * v_a_c_length;
+    let v_b_c = dir_a_to_b * v_b_c_length;
+    let v_a_c_prime_length = (a.mass * v_a_c_length + b.mass * v_b_c_length - b.mass * (v_a_c_length - v_b_c_length) * bounciness) / (a.mass + b.mass);
+    let v_b_c_prime_length = (b.mass * v_b_c_length + a.mass * v_a_c_length - a.mass * (v_b_c_length - v_a_c_length) * bounciness) / (a.mass + b.mass);
+    let v_a_c_prime = dir_a_to_b * zero_in(bounce_round_to_zero_threshold, v_a_c_prime_length);
+    let v_b_c_prime = dir_a_to_b * zero_in(bounce_round_to_zero_threshold, v_b_c_prime_length);
+    let new_speed_a = a.speed - &v_a_c + &v_a_c_prime;
+    let new_speed_b = b.speed - &v_b_c + &v_b_c_prime;
+    let acceleration_a = (a.speed - &new_speed_a).length();
+    let acceleration_b = (b.speed - &new_speed_b).length();
+    if acceleration_a > 10.0 {
+        a.extra_brightness = (acceleration_a * flash_strength).max(a.extra_brightness);
+    }
+    if acceleration_b > 10.0 {
+        b.extra_brightness = (acceleration_b * flash_strength).max(b.extra_brightness);
+    }
+    let normal_impulse = a.mass * (dot(&new_speed_a, &dir_a_to_b) - v_a_c_length).abs();
+    a.speed = new_speed_a;
+    b.speed = new_speed_b;
+    let impulse = friction_impulse(a, b, &dir_a_to_b, normal_impulse, contact_friction);
+    a.speed = a.speed + &(impulse / a.mass);
+    b.speed = b.speed - &(impulse / b.mass);
+    a.angular_velocity = a.angular_velocity +
+        &(cross(&(dir_a_to_b * a.radius), &impulse) / a.moment_of_inertia());
+    b.angular_velocity = b.angular_velocity +
+        &(cross(&(dir_a_to_b * b.radius), &impulse) / b.moment_of_inertia());
+}
+
+fn bounce_pairs(spheres: &[Sphere], pairs: &[(usize, usize)], bounciness: f64,
+                flash_strength: f64, bounce_round_to_zero_threshold: f64,
+                contact_friction: f64) -> Vec<Sphere> {
+    let mut new_spheres = spheres.to_vec();
+    for (i, j) in pairs {
+        let (a, b) = pair_mut(&mut new_spheres, *i, *j);
+        let diff = b.center - &a.center;
+        let dist = diff.length();
+        let min_dist = a.radius + b.radius;
+        if dist < min_dist {
+            bounce_pair(a, b, bounciness, flash_strength, bounce_round_to_zero_threshold,
+                        contact_friction);
+        }
+    }
+    new_spheres
+}
+
+// Fraction of the last move (`center_old` to `center`) at which two spheres start touching,
+// if the overlap test after the move would miss the collision or resolve it on the wrong side.
+fn time_of_impact(a: &Sphere, b: &Sphere) -> Option<f64> {
+    let diff_old = b.center_old - &a.center_old;
+    let diff = b.center - &a.center;
+    let min_dist = a.radius + b.radius;
+    let overlap_handles_it = diff.length() < min_dist && dot(&diff_old, &diff) > 0.0;
+    let c = diff_old.length_squared() - min_dist * min_dist;
+    if overlap_handles_it || c < 0.0 {
+        return None;
+    }
+    let relative_move = diff - &diff_old;
+    let move_squared_length = relative_move.length_squared();
+    let half_b = dot(&diff_old, &relative_move);
+    let discriminant = half_b * half_b - move_squared_length * c;
+    if move_squared_length < 1e-12 || discriminant < 0.0 {
+        return None;
+    }
+    let fraction = (-half_b - discriminant.sqrt()) / move_squared_length;
+    if (0.0..=1.0).contains(&fraction) { Some(fraction) } else { None }
+}
+
+// Earliest impact of two spheres during their last move, as fraction of it
+fn earliest_impact(spheres: &[Sphere]) -> Option<(f64, usize, usize)> {
+    // Spheres covering the whole way, so the broad phase finds all crossing paths.
+    let swept_spheres = spheres.iter().map(|sphere| {
+        Sphere {
+            center: blend_vectors(&sphere.center, &sphere.center_old, 0.5),
+            radius: sphere.radius + 0.5 * (sphere.center - &sphere.center_old).length(),
+            ..sphere.clone()
+        }
+    }).collect::<Vec<Sphere>>();
+    candidate_pairs(&swept_spheres).into_iter().filter_map(|(i, j)| {
+        time_of_impact(&spheres[i], &spheres[j]).map(|fraction| (fraction, i, j))
+    }).min_by(|a, b| a.0.partial_cmp(&b.0).expect("Invalid time of impact"))
+}
+
+/// Continuous collision detection: Finds spheres whose paths crossed during the last move,
+/// bounces them at the time of impact, and moves them on with their new speeds.
+/// Also returns which spheres bounced.
+pub fn bounce_swept(spheres: &[Sphere], delta_t: f64, bounciness: f64, flash_strength: f64,
+                    bounce_round_to_zero_threshold: f64, contact_friction: f64)
+                    -> (Vec<Sphere>, Vec<bool>) {
+    // Impacts left after that many are handled by the overlap-based `bounce`.
+    const MAX_IMPACTS: usize = 100;
+    let mut new_spheres = spheres.to_vec();
+    let mut bounced = vec![false; spheres.len()];
+    let mut remaining_delta_t = delta_t;
+    // Handles one impact after the other, because each one changes the paths of the spheres.
+    for _ in 0..MAX_IMPACTS {
+        let (fraction, i, j) = match earliest_impact(&new_spheres) {
+            Some(impact) => impact,
+            None => break,
+        };
+        for sphere in new_spheres.iter_mut() {
+            sphere.center_old = blend_vectors(&sphere.center, &sphere.center_old, fraction);
+        }
+        remaining_delta_t *= 1.0 - fraction;
+        let (a, b) = pair_mut(&mut new_spheres, i, j);
+        a.center = a.center_old;
+        b.center = b.center_old;
+        bounce_pair(a, b, bounciness, flash_strength, bounce_round_to_zero_threshold,
+                    contact_friction);
+        // Only their paths changed, the others keep the end positions of the integrator.
+        a.center = a.center_old + &(a.speed * remaining_delta_t);
+        b.center = b.center_old + &(b.speed * remaining_delta_t);
+        bounced[i] = true;
+        bounced[j] = true;
+    }
+    for (new_sphere, sphere) in new_spheres.iter_mut().zip(spheres.iter()) {
+        new_sphere.center_old = sphere.center_old;
+    }
+    (new_spheres, bounced)
+}
+
+/// Which spheres overlap (i.e., touch) at least one other sphere
+pub fn touching(spheres: &[Sphere]) -> Vec<bool> {
+    let mut touching = vec![false; spheres.len()];
+    for (i, j) in candidate_pairs(spheres) {
+        let (a, b) = (&spheres[i], &spheres[j]);
+        if (b.center - &a.center).length() < a.radius + b.radius {
+            touching[i] = true;
+            touching[j] = true;
+        }
+    }
+    touching
+}
+
+pub fn solve_non_overlapping_constraint(spheres: &[Sphere]) -> Vec<Sphere> {
+    solve_non_overlapping_constraint_with(spheres, candidate_pairs)
+}
+
+fn solve_non_overlapping_constraint_with<F>(spheres: &[Sphere], find_pairs: F) -> Vec<Sphere>
+    where
+        F: Fn(&[Sphere]) -> Vec<(usize, usize)>,
+{
     let mut change = true;
-    let mut spheres_copy = spheres.to_vec();
-    let new_spheres = spheres_copy.iter_mut()
-        .map(|s| RefCell::new(s))
-        .collect::<Vec<RefCell<&mut Sphere>>>();
+    let mut new_spheres = spheres.to_vec();
     while change {
         change = false;
-        new_spheres.iter().combinations(2).for_each(|pair| {
-            if let [a, b] = pair.as_slice() {
-                let mut a = a.borrow_mut();
-                let mut b = b.borrow_mut();
-                let diff = b.center - &a.center;
-                let dist = diff.length();
-                let min_dist = a.radius + b.radius;
-                if dist < min_dist {
-                    let move_fraction_a = b.mass / (b.mass + a.mass);
-                    let move_fraction_b = 1.0 - move_fraction_a;
-                    let move_dist = min_dist - dist;
-                    let dir_b_to_a = unit_vector(&(a.center - &b.center));
-                    let dir_a_to_b = unit_vector(&(b.center - &a.center));
-                    a.center = a.center + &(dir_b_to_a * move_dist * move_fraction_a) + &(dir_b_to_a * 0.00000001);
-                    b.center = b.center + &(dir_a_to_b * move_dist * move_fraction_b) + &(dir_a_to_b * 0.00000001);
-                    change = true;
-                }
+        for (i, j) in find_pairs(&new_spheres) {
+            let (a, b) = pair_mut(&mut new_spheres, i, j);
+            let diff = b.center - &a.center;
+            let dist = diff.length();
+            let min_dist = a.radius + b.radius;
+            if dist < min_dist {
+                let move_fraction_a = b.mass / (b.mass + a.mass);
+                let move_fraction_b = 1.0 - move_fraction_a;
+                let move_dist = min_dist - dist;
+                let dir_b_to_a = unit_vector(&(a.center - &b.center));
+                let dir_a_to_b = unit_vector(&(b.center - &a.center));
+                a.center = a.center + &(dir_b_to_a * move_dist * move_fraction_a) + &(dir_b_to_a * 0.00000001);
+                b.center = b.center + &(dir_a_to_b * move_dist * move_fraction_b) + &(dir_a_to_b * 0.00000001);
+                change = true;
             }
-        })
+        }
     }
-    new_spheres.iter().map(|s| {
-        s.borrow().deref().deref().clone()
-    }).collect()
+    new_spheres
 }
 
-pub fn move_positions(spheres: &Vec<Sphere>, delta_t: f64) -> Vec<Sphere> {
+pub fn move_positions(spheres: &[Sphere], delta_t: f64) -> Vec<Sphere> {
     spheres.iter().map(|sphere| {
         Sphere {
             center_old: sphere.center,
             center: sphere.center + &(sphere.speed * delta_t),
-            ..*sphere
+            ..sphere.clone()
         }
     }).collect()
 }
 
-pub fn dim(spheres: &Vec<Sphere>, delta_t: f64,
+pub fn rotate(spheres: &[Sphere], delta_t: f64) -> Vec<Sphere> {
+    spheres.iter().map(|sphere| {
+        Sphere {
+            orientation_old: sphere.orientation,
+            orientation: (Quaternion::from_rotation_vector(&(sphere.angular_velocity * delta_t)) *
+                &sphere.orientation).normalized(),
+            ..sphere.clone()
+        }
+    }).collect()
+}
+
+pub fn dim(spheres: &[Sphere], delta_t: f64,
            dim_factor: f64, dim_constant: f64) -> Vec<Sphere> {
     spheres.iter().map(|sphere| {
         Sphere {
             extra_brightness: (sphere.extra_brightness -
                 (sphere.extra_brightness * dim_factor * delta_t +
                     dim_constant * delta_t)).max(0.0),
-            ..*sphere
+            ..sphere.clone()
         }
     }).collect()
 }
 
-pub fn friction(spheres: &Vec<Sphere>, delta_t: f64, friction: f64) -> Vec<Sphere> {
+/// Rolling resistance only slows down the spin of spheres `in_contact` with others,
+/// so spheres in free flight keep spinning.
+pub fn friction(spheres: &[Sphere], in_contact: &[bool], delta_t: f64, friction: f64,
+                rolling_resistance: f64) -> Vec<Sphere> {
     let deceleration_factor = delta_t * friction;
+    let spin_deceleration_factor = (delta_t * rolling_resistance).min(1.0);
     // Something like the following would be a more realistic representation of air resistance:
     // delta_t * friction * sphere.radius.powf(2.0) / sphere.mass.powf(3.0)
     // But the other version looks nicer in the animation.
-    spheres.iter().map(|sphere| {
+    spheres.iter().zip(in_contact.iter()).map(|(sphere, &in_contact)| {
         Sphere {
             speed: sphere.speed - &(sphere.speed * deceleration_factor),
-            ..*sphere
+            angular_velocity: if in_contact {
+                sphere.angular_velocity - &(sphere.angular_velocity * spin_deceleration_factor)
+            } else {
+                sphere.angular_velocity
+            },
+            ..sphere.clone()
         }
     }).collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+    use rand::prelude::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use crate::raytracer::color::Color;
+    use crate::raytracer::material::Material;
+    use crate::raytracer::texture::Texture;
+    use crate::raytracer::world::World;
+
+    use super::*;
+
+    fn sphere(center: Vector3d, radius: f64, speed: Vector3d) -> Sphere {
+        Sphere {
+            center,
+            radius,
+            material: Material {
+                albedo: Texture::Solid(Color::black()),
+                reflectiveness: 0.0,
+                reflection_fuzz: 0.0,
+                transparency: 0.0,
+                refraction_index: 1.5,
+                absorption: Color::black(),
+                emission: Color::black(),
+                flash_emission: 0.0,
+            },
+            speed,
+            mass: radius.powf(3.0),
+            extra_brightness: 0.0,
+            center_old: center,
+            angular_velocity: Vector3d::null(),
+            orientation: Quaternion::identity(),
+            orientation_old: Quaternion::identity(),
+        }
+    }
+
+    fn random_spheres_on_planet(rng: &mut StdRng) -> Vec<Sphere> {
+        let mut spheres: Vec<Sphere> = (0..400).map(|_| {
+            sphere(Vector3d {
+                x: rng.gen_range(-10.0, 10.0),
+                y: rng.gen_range(-0.5, 10.0),
+                z: rng.gen_range(-10.0, 10.0),
+            }, rng.gen_range(0.3, 1.5), Vector3d {
+                x: rng.gen_range(-30.0, 30.0),
+                y: rng.gen_range(-30.0, 30.0),
+                z: rng.gen_range(-30.0, 30.0),
+            })
+        }).collect();
+        spheres.push(sphere(Vector3d { x: 0.0, y: -6371.0, z: 0.0 }, 6371.0, Vector3d::null()));
+        spheres
+    }
+
+    fn all_pairs(spheres: &[Sphere]) -> Vec<(usize, usize)> {
+        (0..spheres.len()).tuple_combinations().collect()
+    }
+
+    #[test]
+    fn test_broad_phase_finds_all_overlapping_pairs() {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let spheres = random_spheres_on_planet(&mut rng);
+        let candidates = candidate_pairs(&spheres);
+        let overlapping = all_pairs(&spheres).into_iter().filter(|(i, j)| {
+            let (a, b) = (&spheres[*i], &spheres[*j]);
+            (b.center - &a.center).length() < a.radius + b.radius
+        }).collect::<Vec<_>>();
+        assert!(overlapping.len() > 100);
+        assert!(overlapping.iter().all(|pair| candidates.binary_search(pair).is_ok()));
+    }
+
+    #[test]
+    fn test_bounce_equals_pairwise() {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(1);
+        let spheres = random_spheres_on_planet(&mut rng);
+        let expected = bounce_pairs(&spheres, &all_pairs(&spheres), 0.46, 0.006, 10.0, 0.4);
+        let result = bounce(&spheres, 0.46, 0.006, 10.0, 0.4);
+        assert!(result.iter().zip(expected.iter()).all(|(a, b)| {
+            a.speed == b.speed && a.angular_velocity == b.angular_velocity &&
+                a.extra_brightness == b.extra_brightness
+        }));
+    }
+
+    #[test]
+    fn test_solve_non_overlapping_constraint_equals_pairwise() {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(2);
+        let spheres = random_spheres_on_planet(&mut rng);
+        let expected = solve_non_overlapping_constraint_with(&spheres, all_pairs);
+        let result = solve_non_overlapping_constraint(&spheres);
+        assert!(result.iter().zip(expected.iter()).all(|(a, b)| {
+            (a.center - &b.center).length() < 1e-9
+        }));
+    }
+
+    #[test]
+    fn test_collision_flash_emission() {
+        let white_ball = |x: f64, speed_x: f64, flash_emission: f64| {
+            let ball = sphere(Vector3d { x, y: 0.0, z: 0.0 }, 1.0,
+                              Vector3d { x: speed_x, y: 0.0, z: 0.0 });
+            Sphere {
+                material: Material {
+                    albedo: Texture::Solid(Color::white()),
+                    flash_emission,
+                    ..ball.material
+                },
+                ..ball
+            }
+        };
+        let collide = |flash_emission: f64| bounce(&[
+            white_ball(-0.99, 20.0, flash_emission),
+            white_ball(0.99, -20.0, flash_emission),
+        ], 0.5, 0.006, 0.0, 0.0);
+
+        let dark = collide(0.0);
+        assert!(dark[0].extra_brightness > 0.0);
+        assert_eq!(dark[0].emission(), Color::black());
+
+        let glowing = collide(2.0);
+        let brighter = collide(4.0);
+        assert_eq!(glowing[0].emission(), Color::white() * glowing[0].extra_brightness * 2.0);
+        assert_eq!(brighter[0].emission(), glowing[0].emission() * 2.0);
+        assert_eq!(World::new(glowing).emitters(), &[0, 1]);
+    }
+
+    #[test]
+    fn test_consecutive_impacts_within_one_step() {
+        // The first sphere hits the two thin ones in a row during the step.
+        let radius = 0.01;
+        let spheres = move_positions(&[
+            sphere(Vector3d { x: -1.0, y: 0.0, z: 0.0 }, radius,
+                   Vector3d { x: 100.0, y: 0.0, z: 0.0 }),
+            sphere(Vector3d { x: 0.0, y: 0.0, z: 0.0 }, radius, Vector3d::null()),
+            sphere(Vector3d { x: 0.5, y: 0.0, z: 0.0 }, radius, Vector3d::null()),
+        ], 0.1);
+        let (bounced, in_contact) = bounce_swept(&spheres, 0.1, 1.0, 0.0, 0.0, 0.0);
+        assert_eq!(in_contact, vec![true, true, true]);
+        let speeds = bounced.iter().map(|s| s.speed.x).collect::<Vec<f64>>();
+        assert_eq!(speeds, vec![0.0, 0.0, 100.0]);
+        // Impacts after 0.0098 and 0.0146 time units
+        assert!((bounced[0].center.x - -0.02).abs() < 1e-9);
+        assert!((bounced[1].center.x - 0.48).abs() < 1e-9);
+        assert!((bounced[2].center.x - (0.5 + 100.0 * (0.1 - 0.0146))).abs() < 1e-9);
+        assert!(bounced.iter().zip(spheres.iter()).all(|(a, b)| a.center_old == b.center_old));
+    }
+
+    #[test]
+    fn test_contact_friction_makes_spheres_roll() {
+        let ground = sphere(Vector3d { x: 0.0, y: -1000.0, z: 0.0 }, 1000.0, Vector3d::null());
+        let ball = sphere(Vector3d { x: 0.0, y: 0.99, z: 0.0 }, 1.0,
+                          Vector3d { x: 5.0, y: -5.0, z: 0.0 });
+        let sliding = bounce(&[ball.clone(), ground.clone()], 0.5, 0.0, 0.0, 0.0);
+        assert_eq!(sliding[0].angular_velocity, Vector3d::null());
+        assert_eq!(sliding[0].speed.x, 5.0);
+
+        let rolling = bounce(&[ball, ground], 0.5, 0.0, 0.0, 10.0);
+        let ball = &rolling[0];
+        let contact_speed = ball.speed +
+            &cross(&ball.angular_velocity, &Vector3d { x: 0.0, y: -ball.radius, z: 0.0 });
+        assert!(ball.speed.x > 0.0 && ball.speed.x < 5.0);
+        assert!(ball.angular_velocity.z < 0.0);
+        assert!(contact_speed.x.abs() < 1e-6);
+    }
+}
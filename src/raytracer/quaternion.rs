@@ -0,0 +1,72 @@
+use std::ops::Mul;
+
+use crate::raytracer::vector3d::{cross, dot, Vector3d};
+
+/// Unit quaternion representing a rotation
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Quaternion {
+    pub w: f64,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+impl Quaternion {
+    pub fn identity() -> Quaternion {
+        Quaternion { w: 1.0, x: 0.0, y: 0.0, z: 0.0 }
+    }
+
+    /// Rotation around `axis` (any length) by `rotation_vector.length()` radians,
+    /// as produced by an angular velocity over a time step
+    pub fn from_rotation_vector(rotation_vector: &Vector3d) -> Quaternion {
+        let angle = rotation_vector.length();
+        if angle < 1e-12 {
+            return Quaternion::identity();
+        }
+        let axis = *rotation_vector * ((0.5 * angle).sin() / angle);
+        Quaternion { w: (0.5 * angle).cos(), x: axis.x, y: axis.y, z: axis.z }
+    }
+
+    fn vector_part(&self) -> Vector3d {
+        Vector3d { x: self.x, y: self.y, z: self.z }
+    }
+
+    pub fn conjugate(&self) -> Quaternion {
+        Quaternion { w: self.w, x: -self.x, y: -self.y, z: -self.z }
+    }
+
+    pub fn normalized(&self) -> Quaternion {
+        let length = (self.w * self.w + self.x * self.x + self.y * self.y + self.z * self.z).sqrt();
+        Quaternion { w: self.w / length, x: self.x / length, y: self.y / length, z: self.z / length }
+    }
+
+    #[inline(always)]
+    pub fn rotate(&self, v: &Vector3d) -> Vector3d {
+        let u = self.vector_part();
+        let t = cross(&u, v) * 2.0;
+        *v + &(t * self.w) + &cross(&u, &t)
+    }
+}
+
+impl Mul<&Quaternion> for Quaternion {
+    type Output = Quaternion;
+    fn mul(self, other: &Quaternion) -> Quaternion {
+        let (a, b) = (self.vector_part(), other.vector_part());
+        let v = b * self.w + &(a * other.w) + &cross(&a, &b);
+        Quaternion { w: self.w * other.w - dot(&a, &b), x: v.x, y: v.y, z: v.z }
+    }
+}
+
+/// Normalized linear interpolation, `factor_q1 = 1` yields `q1`.
+#[inline(always)]
+pub fn blend_quaternions(q1: &Quaternion, q2: &Quaternion, factor_q1: f64) -> Quaternion {
+    // Take the shorter way around.
+    let sign = if q1.w * q2.w + q1.x * q2.x + q1.y * q2.y + q1.z * q2.z < 0.0 { -1.0 } else { 1.0 };
+    let f2 = (1.0 - factor_q1) * sign;
+    Quaternion {
+        w: q1.w * factor_q1 + q2.w * f2,
+        x: q1.x * factor_q1 + q2.x * f2,
+        y: q1.y * factor_q1 + q2.y * f2,
+        z: q1.z * factor_q1 + q2.z * f2,
+    }.normalized()
+}
@@ -1,43 +1,112 @@
+use std::f64::consts::PI;
+
 use rand::{Rng, SeedableRng};
 use rand::prelude::StdRng;
 use rayon::prelude::*;
 
-use crate::raytracer::camera::{CameraRange, get_ray_camera_blend};
+use crate::raytracer::camera::{CameraRange, get_pinhole_ray_camera_blend, get_ray_camera_blend};
 use crate::raytracer::color::{blend_colors, Color};
 use crate::raytracer::image::Image;
 use crate::raytracer::ray::Ray;
-use crate::raytracer::vector3d::unit_vector;
+use crate::raytracer::environment_map::{EnvironmentLayer, layers_radiance};
+use crate::raytracer::light::{Light, light_pdf, power_heuristic, sample_direct_light, Sun};
+use crate::raytracer::physical_sky::PhysicalSky;
+use crate::raytracer::vector3d::{dot, unit_vector, Vector3d};
 use crate::raytracer::world::World;
 
+pub enum Background {
+    // Blends from `col2` at the bottom to `col1` at the top.
+    Gradient { col1: Color, col2: Color },
+    Environment(Vec<EnvironmentLayer>),
+    Physical(PhysicalSky),
+}
+
 pub struct Sky {
-    pub col1: Color,
-    pub col2: Color,
+    pub background: Background,
+    pub sun: Option<Sun>,
+}
+
+impl Sky {
+    pub fn environment_layers(&self) -> &[EnvironmentLayer] {
+        match &self.background {
+            Background::Environment(layers) => layers,
+            Background::Gradient { .. } | Background::Physical(_) => &[],
+        }
+    }
+}
+
+// Origin of a ray scattered by a Lambertian surface
+struct DiffuseBounce {
+    position: Vector3d,
+    normal: Vector3d,
+}
+
+// Weight of light found by following the scattered ray,
+// in case it could also have been found by `sample_direct_light`.
+#[inline(always)]
+fn bsdf_weight(world: &World, sky: &Sky, ray: &Ray, previous: Option<&DiffuseBounce>,
+               light: Light) -> f64 {
+    match previous {
+        Some(bounce) => {
+            let direction = unit_vector(&ray.direction);
+            let bsdf_pdf = dot(&bounce.normal, &direction).max(0.0) / PI;
+            let light_pdf = light_pdf(world, sky, &bounce.position, &direction,
+                                      ray.frame_time, light);
+            power_heuristic(bsdf_pdf, light_pdf)
+        }
+        None => 1.0
+    }
 }
 
 #[inline(always)]
 fn ray_color(rng: &mut StdRng, ray: &Ray, world: &World,
-             depth: usize, sky: &Sky) -> Color {
-    if depth <= 0 {
+             depth: usize, sky: &Sky, previous: Option<&DiffuseBounce>) -> Color {
+    if depth == 0 {
         return Color::black();
     }
     let t_min = 0.001;
     let t_max = 9999999999.9;
-    match world.hit(ray, t_min, t_max) {
-        Some(rec) => {
-            return match rec.material.scatter(rng, &ray, &rec) {
-                Some((scattered, attenuation)) => {
-                    attenuation * &ray_color(rng, &scattered,
-                                             world, depth - 1, sky)
-                }
-                None => Color::black()
-            };
-        }
-        None => {}
+    if let Some(rec) = world.hit(ray, t_min, t_max) {
+        let sphere_index = rec.sphere_index.expect("Hit without sphere index");
+        let emitted = if rec.material.emission == Color::black() {
+            Color::black()
+        } else {
+            rec.material.emission *
+                bsdf_weight(world, sky, ray, previous, Light::Sphere(sphere_index))
+        };
+        return match rec.material.scatter(rng, ray, &rec) {
+            Some(scattering) if scattering.is_diffuse => {
+                let direct = sample_direct_light(
+                    rng, world, sky, &rec, &scattering.attenuation, ray.frame_time);
+                let bounce = DiffuseBounce { position: rec.position, normal: rec.normal };
+                emitted + &direct + &(scattering.attenuation * &ray_color(
+                    rng, &scattering.ray, world, depth - 1, sky, Some(&bounce)))
+            }
+            Some(scattering) => {
+                emitted + &(scattering.attenuation * &ray_color(
+                    rng, &scattering.ray, world, depth - 1, sky, None))
+            }
+            None => emitted
+        };
     }
 
-    blend_colors(&sky.col2, &sky.col1, 0.5 * (unit_vector(&ray.direction).y + 1.0))
+    let background = match &sky.background {
+        Background::Gradient { col1, col2 } =>
+            blend_colors(col2, col1, 0.5 * (unit_vector(&ray.direction).y + 1.0)),
+        Background::Environment(layers) =>
+            layers_radiance(layers, &ray.direction) *
+                bsdf_weight(world, sky, ray, previous, Light::Environment),
+        Background::Physical(physical_sky) => physical_sky.radiance(&ray.direction),
+    };
+    match &sky.sun {
+        Some(sun) if sun.is_in_direction(&ray.direction) => {
+            background + &(sun.radiance() * bsdf_weight(world, sky, ray, previous, Light::Sun))
+        }
+        _ => background
+    }
 }
 
+/// Returns the linear radiance, see `Image::tone_mapped` for displaying it.
 pub fn render(width: usize, height: usize,
               samples_per_pixel: usize, max_depth: usize,
               world: &World, cams: &CameraRange, sky: &Sky) -> Image {
@@ -50,7 +119,7 @@ pub fn render(width: usize, height: usize,
                     let vertical_fraction = (y as f64 + rng.gen::<f64>()) / (height as f64 - 1.0);
                     let ray = get_ray_camera_blend(
                         &mut rng, horizontal_fraction, vertical_fraction, cams);
-                    ray_color(&mut rng, &ray, &world, max_depth, sky)
+                    ray_color(&mut rng, &ray, world, max_depth, sky, None)
                 }).fold(Color::black(),
                         |a: Color, b: Color| a + &b)
             }).collect()
@@ -58,9 +127,250 @@ pub fn render(width: usize, height: usize,
             .iter()
             .flatten()
             .map(|c| *c / samples_per_pixel as f64)
-            .map(|c| c.sqrt_gamma_correct())
             .collect(),
         width,
         height,
     }
 }
+
+/// Arbitrary output variables of the primary rays,
+/// e.g., for compositing, denoising or debugging collisions
+pub struct Aovs {
+    // Distance from the camera, infinite where no sphere is hit
+    pub depth: Image,
+    // Outward surface normal in world space
+    pub normal: Image,
+    // Looked up in the texture of the material
+    pub albedo: Image,
+    // -1 where no sphere is hit
+    pub sphere_index: Image,
+    // Movement of the hit sphere during the frame (from `center_old` to `center`) in world space
+    pub motion: Image,
+}
+
+fn vector_to_color(v: &Vector3d) -> Color {
+    Color { r: v.x, g: v.y, b: v.z }
+}
+
+fn aov_values(ray: &Ray, world: &World) -> [Color; 5] {
+    let gray = |value: f64| Color { r: value, g: value, b: value };
+    match world.hit(ray, 0.001, 9999999999.9) {
+        Some(rec) => {
+            let sphere_index = rec.sphere_index.expect("Hit without sphere index");
+            let sphere = &world.spheres()[sphere_index];
+            let outward_normal = if rec.front_face {
+                rec.normal
+            } else {
+                Vector3d::null() - &rec.normal
+            };
+            [
+                gray((rec.position - &ray.origin).length()),
+                vector_to_color(&outward_normal),
+                rec.albedo,
+                gray(sphere_index as f64),
+                vector_to_color(&(sphere.center - &sphere.center_old)),
+            ]
+        }
+        // The background is infinitely far away, so depth-based effects treat it as such.
+        None => [gray(f64::INFINITY), Color::black(), Color::black(), gray(-1.0), Color::black()]
+    }
+}
+
+/// Uses one ray through the center of each pixel in the middle of the exposure,
+/// so the passes line up with the (motion-blurred) image returned by `render`.
+pub fn render_aovs(width: usize, height: usize, world: &World, cams: &CameraRange) -> Aovs {
+    let values = (0..height).into_par_iter().map(|y| {
+        (0..width).map(|x| {
+            let horizontal_fraction = (x as f64 + 0.5) / (width as f64 - 1.0);
+            let vertical_fraction = (y as f64 + 0.5) / (height as f64 - 1.0);
+            let ray = get_pinhole_ray_camera_blend(
+                horizontal_fraction, vertical_fraction, 0.5, cams);
+            aov_values(&ray, world)
+        }).collect()
+    }).collect::<Vec<Vec<[Color; 5]>>>().concat();
+    let pass = |i: usize| Image { data: values.iter().map(|v| v[i]).collect(), width, height };
+    Aovs {
+        depth: pass(0),
+        normal: pass(1),
+        albedo: pass(2),
+        sphere_index: pass(3),
+        motion: pass(4),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::raytracer::camera::Camera;
+    use crate::raytracer::environment_map::EnvironmentMap;
+    use crate::raytracer::material::Material;
+    use crate::raytracer::quaternion::Quaternion;
+    use crate::raytracer::sphere::Sphere;
+    use crate::raytracer::texture::Texture;
+
+    use super::*;
+
+    fn ground(emission: Color) -> Sphere {
+        let center = Vector3d { x: 0.0, y: -1000.0, z: 0.0 };
+        Sphere {
+            center,
+            radius: 1000.0,
+            material: Material {
+                albedo: Texture::Solid(Color { r: 0.5, g: 0.5, b: 0.5 }),
+                reflectiveness: 0.0,
+                reflection_fuzz: 0.0,
+                transparency: 0.0,
+                refraction_index: 1.5,
+                absorption: Color::black(),
+                emission,
+                flash_emission: 0.0,
+            },
+            speed: Vector3d::null(),
+            mass: 1.0,
+            extra_brightness: 0.0,
+            center_old: center,
+            angular_velocity: Vector3d::null(),
+            orientation: Quaternion::identity(),
+            orientation_old: Quaternion::identity(),
+        }
+    }
+
+    #[test]
+    fn test_sunlit_lambertian_surface() {
+        let world = World::new(vec![ground(Color::black())]);
+        let sky = Sky {
+            background: Background::Gradient { col1: Color::black(), col2: Color::black() },
+            sun: Some(Sun {
+                direction: Vector3d { x: 0.0, y: 1.0, z: 0.0 },
+                color: Color::white(),
+                angular_radius: 5.0,
+            }),
+        };
+        let ray = Ray {
+            origin: Vector3d { x: 0.0, y: 1.0, z: 0.0 },
+            direction: Vector3d { x: 0.0, y: -1.0, z: 0.0 },
+            frame_time: 0.0,
+        };
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let num_samples = 100000;
+        let mean = (0..num_samples)
+            .map(|_| ray_color(&mut rng, &ray, &world, 2, &sky, None).r)
+            .sum::<f64>() / num_samples as f64;
+        // Irradiance of a uniformly bright disk around the normal is L * PI * sin^2(theta).
+        let theta = 5.0_f64.to_radians();
+        let expected = 0.5 * theta.sin().powf(2.0) / (2.0 * PI * (1.0 - theta.cos()));
+        assert!((mean - expected).abs() < 0.01 * expected, "{} vs. {}", mean, expected);
+    }
+
+    #[test]
+    fn test_lambertian_surface_lit_by_sphere() {
+        let light_center = Vector3d { x: 0.0, y: 5.0, z: 0.0 };
+        let light = Sphere {
+            center: light_center,
+            radius: 0.5,
+            center_old: light_center,
+            angular_velocity: Vector3d::null(),
+            orientation: Quaternion::identity(),
+            orientation_old: Quaternion::identity(),
+            ..ground(Color { r: 10.0, g: 10.0, b: 10.0 })
+        };
+        let world = World::new(vec![ground(Color::black()), light]);
+        let sky = Sky {
+            background: Background::Gradient { col1: Color::black(), col2: Color::black() },
+            sun: None,
+        };
+        let ray = Ray {
+            origin: Vector3d { x: 0.0, y: 1.0, z: 1.0 },
+            direction: Vector3d { x: 0.0, y: -1.0, z: -1.0 },
+            frame_time: 0.0,
+        };
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let num_samples = 100000;
+        let mean = (0..num_samples)
+            .map(|_| ray_color(&mut rng, &ray, &world, 2, &sky, None).r)
+            .sum::<f64>() / num_samples as f64;
+        let expected = 0.5 / PI * 10.0 * PI * (0.5_f64 / 5.0).powf(2.0);
+        assert!((mean - expected).abs() < 0.01 * expected, "{} vs. {}", mean, expected);
+    }
+
+    #[test]
+    fn test_lambertian_surface_lit_by_environment() {
+        let (width, height) = (32, 16);
+        let map = EnvironmentMap::new(width, height, (0..width * height).map(|i| {
+            // A bright spot below the horizon must not change the result.
+            if i == 13 * width + 5 { Color { r: 1000.0, g: 1000.0, b: 1000.0 } } else { Color::white() }
+        }).collect());
+        let world = World::new(vec![ground(Color::black())]);
+        let sky = Sky {
+            background: Background::Environment(vec![EnvironmentLayer {
+                map: Arc::new(map),
+                rotation: 1.0,
+                intensity: 2.0,
+            }]),
+            sun: None,
+        };
+        let ray = Ray {
+            origin: Vector3d { x: 0.0, y: 1.0, z: 1.0 },
+            direction: Vector3d { x: 0.0, y: -1.0, z: -1.0 },
+            frame_time: 0.0,
+        };
+        let mut rng: StdRng = SeedableRng::seed_from_u64(0);
+        let num_samples = 100000;
+        let mean = (0..num_samples)
+            .map(|_| ray_color(&mut rng, &ray, &world, 2, &sky, None).r)
+            .sum::<f64>() / num_samples as f64;
+        let expected = 0.5 * 2.0;
+        assert!((mean - expected).abs() < 0.01 * expected, "{} vs. {}", mean, expected);
+    }
+
+    #[test]
+    fn test_emissive_sphere_as_only_light() {
+        let emission = Color { r: 3.0, g: 2.0, b: 1.0 };
+        let ball = ground(emission);
+        let light = Sphere {
+            center: Vector3d::null(),
+            radius: 1.0,
+            center_old: Vector3d::null(),
+            material: Material { albedo: Texture::Solid(Color::black()), ..ball.material },
+            ..ball
+        };
+        let world = World::new(vec![light]);
+        let sky = Sky {
+            background: Background::Gradient { col1: Color::black(), col2: Color::black() },
+            sun: None,
+        };
+        let cam = || Camera::new(&Vector3d { x: 0.0, y: 0.0, z: 5.0 }, &Vector3d::null(),
+                                 &Vector3d { x: 0.0, y: 1.0, z: 0.0 }, 40.0, 1.0, 0.0, 5.0);
+        let image = render(9, 9, 16, 4, &world, &CameraRange { cam_a: cam(), cam_b: cam() }, &sky);
+        // Black albedo, so nothing but the emission is seen.
+        assert_eq!(image.get(4, 4), &emission);
+        assert_eq!(image.get(0, 0), &Color::black());
+    }
+
+    #[test]
+    fn test_aovs() {
+        let center = Vector3d { x: 0.0, y: 1.0, z: 0.0 };
+        let ball = Sphere {
+            center,
+            radius: 1.0,
+            center_old: center - &Vector3d { x: 0.1, y: 0.0, z: 0.0 },
+            ..ground(Color::black())
+        };
+        let world = World::new(vec![ground(Color::black()), ball]);
+        let cam = || Camera::new(&Vector3d { x: 0.0, y: 1.0, z: 5.0 }, &center,
+                                 &Vector3d { x: 0.0, y: 1.0, z: 0.0 }, 40.0, 1.0, 0.0, 5.0);
+        let aovs = render_aovs(9, 9, &world, &CameraRange { cam_a: cam(), cam_b: cam() });
+        // Front of the ball, facing the camera
+        assert!((aovs.depth.get(4, 4).r - 4.0).abs() < 0.1);
+        assert!(aovs.normal.get(4, 4).b > 0.95);
+        assert_eq!(aovs.albedo.get(4, 4), &Color { r: 0.5, g: 0.5, b: 0.5 });
+        assert_eq!(aovs.sphere_index.get(4, 4).r, 1.0);
+        // Only the ball moves, neither the sky (top) nor the ground (bottom).
+        assert!((aovs.motion.get(4, 4).r - 0.1).abs() < 1e-12);
+        assert_eq!(aovs.sphere_index.get(4, 8).r, -1.0);
+        assert_eq!(aovs.depth.get(4, 8).r, f64::INFINITY);
+        assert_eq!(aovs.sphere_index.get(4, 0).r, 0.0);
+        assert_eq!(aovs.motion.get(4, 0), &Color::black());
+    }
+}
@@ -1,9 +1,14 @@
+use std::f64::consts::PI;
+
+use crate::raytracer::color::Color;
 use crate::raytracer::hit::{face_normal, Hit};
 use crate::raytracer::material::Material;
+use crate::raytracer::quaternion::{blend_quaternions, Quaternion};
 use crate::raytracer::ray::Ray;
+use crate::raytracer::texture::Texture;
 use crate::raytracer::vector3d::{blend_vectors, dot, is_in_interval, Vector3d};
 
-#[derive(Copy, Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Sphere {
     pub center: Vector3d,
     pub radius: f64,
@@ -12,24 +17,50 @@ pub struct Sphere {
     pub mass: f64,
     pub extra_brightness: f64,
     pub center_old: Vector3d,
+    // Radians per unit of time around the direction of the vector
+    pub angular_velocity: Vector3d,
+    // Rotates the texture
+    pub orientation: Quaternion,
+    pub orientation_old: Quaternion,
 }
 
 impl Sphere {
     #[inline(always)]
     fn calculate_hit(&self, ray: &Ray, t: f64, center: &Vector3d) -> Hit {
         let p = ray.at(t);
-        let outward_normal = (p - &center) / self.radius;
+        let outward_normal = (p - center) / self.radius;
         let (front_face, normal) = face_normal(ray, &outward_normal);
+        let local_normal = self.orientation_at_frame_time(ray.frame_time)
+            .conjugate().rotate(&outward_normal);
+        let u = ((-local_normal.z).atan2(local_normal.x) + PI) / (2.0 * PI);
+        let v = (-local_normal.y).clamp(-1.0, 1.0).acos() / PI;
+        let albedo = self.material.albedo.value(u, v, &(local_normal * self.radius));
+        // The texture is already looked up, so the hit does not need to share it.
+        let (material, albedo) = if self.material.flash_emission > 0.0 {
+            (Material {
+                albedo: Texture::Solid(albedo),
+                emission: self.emission(),
+                ..self.material
+            }, albedo)
+        } else {
+            let albedo = albedo + &(albedo * self.extra_brightness);
+            (Material {
+                albedo: Texture::Solid(albedo),
+                reflectiveness: self.material.reflectiveness - self.extra_brightness,
+                reflection_fuzz: self.material.reflection_fuzz + self.extra_brightness,
+                ..self.material
+            }, albedo)
+        };
         Hit {
             position: p,
             t,
             normal,
             front_face,
-            material: Material {
-                albedo: self.material.albedo + &(self.material.albedo * self.extra_brightness),
-                reflectiveness: self.material.reflectiveness - self.extra_brightness,
-                reflection_fuzz: self.material.reflection_fuzz + self.extra_brightness,
-            },
+            material,
+            u,
+            v,
+            albedo,
+            sphere_index: None,
         }
     }
 
@@ -55,8 +86,28 @@ impl Sphere {
         None
     }
 
+    /// Light emitted by the surface, including collision flashes if enabled
+    pub fn emission(&self) -> Color {
+        if self.material.flash_emission > 0.0 {
+            self.material.emission + &(self.material.albedo.average() *
+                self.extra_brightness * self.material.flash_emission)
+        } else {
+            self.material.emission
+        }
+    }
+
     #[inline(always)]
     pub fn center_at_frame_time(&self, frame_time: f64) -> Vector3d {
-        return blend_vectors(&self.center, &self.center_old, frame_time);
+        blend_vectors(&self.center, &self.center_old, frame_time)
+    }
+
+    #[inline(always)]
+    pub fn orientation_at_frame_time(&self, frame_time: f64) -> Quaternion {
+        blend_quaternions(&self.orientation, &self.orientation_old, frame_time)
+    }
+
+    // Of a solid ball
+    pub fn moment_of_inertia(&self) -> f64 {
+        0.4 * self.mass * self.radius * self.radius
     }
 }
@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::raytracer::color::{blend_colors, Color, srgb_decode};
+use crate::raytracer::vector3d::Vector3d;
+
+/// Surface color depending on the position on a sphere
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Texture {
+    Solid(Color),
+    // Cubes of edge length `size`, fixed to the sphere
+    Checker { even: Color, odd: Color, size: f64 },
+    // Fractal Perlin noise, features of roughly `size`
+    Noise {
+        color1: Color,
+        color2: Color,
+        size: f64,
+        #[serde(default = "default_octaves")]
+        octaves: usize,
+    },
+    // PNG in equirectangular projection
+    Image {
+        image: PathBuf,
+        // Set by `load_image`
+        #[serde(skip)]
+        loaded: Option<Arc<ImageTexture>>,
+    },
+}
+
+fn default_octaves() -> usize {
+    4
+}
+
+impl Texture {
+    /// `u` and `v` are spherical coordinates in `[0, 1]`,
+    /// `point` is relative to the center of the sphere.
+    #[inline(always)]
+    pub fn value(&self, u: f64, v: f64, point: &Vector3d) -> Color {
+        match self {
+            Texture::Solid(color) => *color,
+            Texture::Checker { even, odd, size } => {
+                let parity = (point.x / size).floor() + (point.y / size).floor() +
+                    (point.z / size).floor();
+                if parity.rem_euclid(2.0) < 1.0 { *even } else { *odd }
+            }
+            Texture::Noise { color1, color2, size, octaves } => {
+                let value = fractal_noise(&(*point / *size), *octaves);
+                blend_colors(color1, color2, (0.5 * (value + 1.0)).clamp(0.0, 1.0))
+            }
+            Texture::Image { loaded: Some(image), .. } => image.value(u, v),
+            Texture::Image { image, loaded: None } =>
+                panic!("Image texture {} has not been loaded", image.display()),
+        }
+    }
+
+    /// Mean color over the whole surface, e.g., for the light emitted by collision flashes
+    pub fn average(&self) -> Color {
+        match self {
+            Texture::Solid(color) => *color,
+            Texture::Checker { even, odd, .. } => blend_colors(even, odd, 0.5),
+            Texture::Noise { color1, color2, .. } => blend_colors(color1, color2, 0.5),
+            Texture::Image { loaded: Some(image), .. } => image.average,
+            Texture::Image { image, loaded: None } =>
+                panic!("Image texture {} has not been loaded", image.display()),
+        }
+    }
+
+    /// Reads the file of an image texture, with its path relative to `base_dir`.
+    /// `loaded_images` makes sure every file is only read once, even if many materials use it.
+    pub fn load_image(&mut self, base_dir: &Path,
+                      loaded_images: &mut HashMap<PathBuf, Arc<ImageTexture>>)
+                      -> Result<(), String> {
+        if let Texture::Image { image, loaded } = self {
+            let path = base_dir.join(&image);
+            let texture = match loaded_images.get(&path) {
+                Some(texture) => texture.clone(),
+                None => {
+                    let texture = Arc::new(ImageTexture::load(&path)?);
+                    loaded_images.insert(path.clone(), texture.clone());
+                    texture
+                }
+            };
+            // Keeps working when saved to a checkpoint, which has no base directory.
+            *image = path;
+            *loaded = Some(texture);
+        }
+        Ok(())
+    }
+}
+
+pub struct ImageTexture {
+    path: String,
+    width: usize,
+    height: usize,
+    // Rows from top to bottom
+    data: Vec<Color>,
+    average: Color,
+}
+
+impl std::fmt::Debug for ImageTexture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ImageTexture({}, {}x{})", self.path, self.width, self.height)
+    }
+}
+
+impl ImageTexture {
+    pub fn load(path: &Path) -> Result<ImageTexture, String> {
+        let file = File::open(path)
+            .map_err(|e| format!("Unable to open texture {}: {}", path.display(), e))?;
+        let mut decoder = png::Decoder::new(file);
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let (info, mut reader) = decoder.read_info()
+            .map_err(|e| format!("Unable to read texture {}: {}", path.display(), e))?;
+        let mut buffer = vec![0; info.buffer_size()];
+        reader.next_frame(&mut buffer)
+            .map_err(|e| format!("Unable to read texture {}: {}", path.display(), e))?;
+        let channels = info.color_type.samples();
+        let to_linear = |value: u8| srgb_decode(value as f64 / 255.0);
+        let data = buffer.chunks(channels).map(|pixel| {
+            if channels >= 3 {
+                Color { r: to_linear(pixel[0]), g: to_linear(pixel[1]), b: to_linear(pixel[2]) }
+            } else {
+                Color::white() * to_linear(pixel[0])
+            }
+        }).collect::<Vec<Color>>();
+        let average = data.iter().fold(Color::black(), |a, b| a + b) / data.len() as f64;
+        Ok(ImageTexture {
+            path: path.display().to_string(),
+            width: info.width as usize,
+            height: info.height as usize,
+            data,
+            average,
+        })
+    }
+
+    fn value(&self, u: f64, v: f64) -> Color {
+        let x = ((u * self.width as f64) as usize).min(self.width - 1);
+        let y = (((1.0 - v) * self.height as f64) as usize).min(self.height - 1);
+        self.data[y * self.width + x]
+    }
+}
+
+// Pseudo-random, but fixed, permutation of the lattice points
+#[inline(always)]
+fn hash(x: i64, y: i64, z: i64) -> u64 {
+    let mut h = (x as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) ^
+        (y as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F) ^
+        (z as u64).wrapping_mul(0x1656_67B1_9E37_79F9);
+    h ^= h >> 29;
+    h = h.wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    h ^ (h >> 32)
+}
+
+// Dot product with one of 12 gradient directions, as in Perlin's improved noise
+#[inline(always)]
+fn gradient(hash: u64, x: f64, y: f64, z: f64) -> f64 {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 {
+        y
+    } else if h == 12 || h == 14 {
+        x
+    } else {
+        z
+    };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
+
+#[inline(always)]
+fn fade(t: f64) -> f64 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+#[inline(always)]
+fn lerp(t: f64, a: f64, b: f64) -> f64 {
+    a + t * (b - a)
+}
+
+/// Perlin noise in roughly `[-1, 1]`
+pub fn perlin_noise(point: &Vector3d) -> f64 {
+    let (xi, yi, zi) = (point.x.floor(), point.y.floor(), point.z.floor());
+    let (x, y, z) = (point.x - xi, point.y - yi, point.z - zi);
+    let (xi, yi, zi) = (xi as i64, yi as i64, zi as i64);
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+    let corner = |dx: i64, dy: i64, dz: i64| {
+        gradient(hash(xi + dx, yi + dy, zi + dz),
+                 x - dx as f64, y - dy as f64, z - dz as f64)
+    };
+    lerp(w,
+         lerp(v,
+              lerp(u, corner(0, 0, 0), corner(1, 0, 0)),
+              lerp(u, corner(0, 1, 0), corner(1, 1, 0))),
+         lerp(v,
+              lerp(u, corner(0, 0, 1), corner(1, 0, 1)),
+              lerp(u, corner(0, 1, 1), corner(1, 1, 1))))
+}
+
+/// Sum of Perlin noise octaves with halving amplitude (fBm), in roughly `[-1, 1]`
+pub fn fractal_noise(point: &Vector3d, octaves: usize) -> f64 {
+    let mut sum = 0.0;
+    let mut amplitude = 1.0;
+    let mut amplitudes = 0.0;
+    let mut p = *point;
+    for _ in 0..octaves.max(1) {
+        sum += amplitude * perlin_noise(&p);
+        amplitudes += amplitude;
+        amplitude *= 0.5;
+        p = p * 2.0;
+    }
+    sum / amplitudes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::raytracer::image::Image;
+
+    use super::*;
+
+    #[test]
+    fn test_procedural_textures() {
+        let red = Color { r: 1.0, g: 0.0, b: 0.0 };
+        let blue = Color { r: 0.0, g: 0.0, b: 1.0 };
+        let checker = Texture::Checker { even: red, odd: blue, size: 2.0 };
+        let at = |x: f64| checker.value(0.0, 0.0, &Vector3d { x, y: 0.5, z: 0.5 });
+        assert_eq!(at(0.5), red);
+        assert_eq!(at(2.5), blue);
+        assert_eq!(at(-0.5), blue);
+
+        let noise = Texture::Noise { color1: red, color2: blue, size: 0.3, octaves: 4 };
+        let values = (0..1000).map(|i| {
+            noise.value(0.0, 0.0, &Vector3d { x: i as f64 * 0.037, y: 0.3, z: -1.7 })
+        }).collect::<Vec<Color>>();
+        assert!(values.iter().all(|c| c.g == 0.0 && (c.r + c.b - 1.0).abs() < 1e-9));
+        assert!(values.iter().any(|c| c.r > 0.6) && values.iter().any(|c| c.b > 0.6));
+    }
+
+    #[test]
+    fn test_image_texture() {
+        let path = std::env::temp_dir()
+            .join(format!("bouncing_spheres_test_image_texture_{}.png", std::process::id()));
+        // Saved upside down, i.e., the white pixel ends up in the top right.
+        Image {
+            data: vec![Color::black(), Color::black(), Color::black(), Color::white()],
+            width: 2,
+            height: 2,
+        }.save_png(&path);
+        let texture = ImageTexture::load(&path).unwrap();
+        assert_eq!(texture.value(0.75, 0.75), Color::white());
+        assert_eq!(texture.value(0.25, 0.75), Color::black());
+        assert_eq!(texture.value(0.75, 0.25), Color::black());
+        assert_eq!(texture.average, Color::white() * 0.25);
+    }
+}
@@ -0,0 +1,75 @@
+use crate::raytracer::color::Color;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ToneMappingOperator {
+    // Highlights become flat white.
+    Clip,
+    Reinhard,
+    // Filmic curve (Narkowicz's fit of the ACES reference rendering transform)
+    Aces,
+}
+
+/// Output stage turning linear radiance into sRGB-encoded values for display
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct ToneMapping {
+    pub operator: ToneMappingOperator,
+    // In stops, i.e., the radiance is scaled by `2^exposure`.
+    pub exposure: f64,
+}
+
+impl Default for ToneMapping {
+    fn default() -> Self {
+        ToneMapping { operator: ToneMappingOperator::Clip, exposure: 0.0 }
+    }
+}
+
+fn reinhard(x: f64) -> f64 {
+    x / (1.0 + x)
+}
+
+fn aces(x: f64) -> f64 {
+    let (a, b, c, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+    ((x * (a * x + b)) / (x * (c * x + d) + e)).clamp(0.0, 1.0)
+}
+
+impl ToneMapping {
+    pub fn apply(&self, radiance: &Color) -> Color {
+        let exposed = *radiance * 2.0_f64.powf(self.exposure);
+        let curve: fn(f64) -> f64 = match self.operator {
+            ToneMappingOperator::Clip => |x| x.clamp(0.0, 1.0),
+            ToneMappingOperator::Reinhard => reinhard,
+            ToneMappingOperator::Aces => aces,
+        };
+        Color {
+            r: curve(exposed.r.max(0.0)),
+            g: curve(exposed.g.max(0.0)),
+            b: curve(exposed.b.max(0.0)),
+        }.srgb_encoded()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_operators_keep_highlights_apart() {
+        let gray = |value: f64| Color { r: value, g: value, b: value };
+        for operator in &[ToneMappingOperator::Clip, ToneMappingOperator::Reinhard,
+            ToneMappingOperator::Aces] {
+            let tone_mapping = ToneMapping { operator: *operator, exposure: 0.0 };
+            let values = [0.0, 0.1, 0.5, 1.0, 2.0, 10.0].iter()
+                .map(|&v| tone_mapping.apply(&gray(v)).r)
+                .collect::<Vec<f64>>();
+            assert_eq!(values[0], 0.0);
+            assert!(values.windows(2).all(|w| w[0] <= w[1] && w[1] <= 1.0));
+            // Only clipping maps different highlights to the same white.
+            let distinct_highlights = values[4] < values[5];
+            assert_eq!(distinct_highlights, *operator != ToneMappingOperator::Clip);
+        }
+        let brighter = ToneMapping { operator: ToneMappingOperator::Clip, exposure: 1.0 };
+        assert_eq!(brighter.apply(&gray(0.25)), ToneMapping::default().apply(&gray(0.5)));
+    }
+}
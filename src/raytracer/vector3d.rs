@@ -5,7 +5,7 @@ use std::ops::{Add, Div, Mul, Sub};
 use rand::prelude::StdRng;
 use rand::Rng;
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct Vector3d {
     pub x: f64,
     pub y: f64,
@@ -102,11 +102,35 @@ pub fn random_unit_vector(rng: &mut StdRng) -> Vector3d {
     }
 }
 
+// Uniformly distributed in the cone around `axis` (unit vector)
+#[inline(always)]
+pub fn random_in_cone(rng: &mut StdRng, axis: &Vector3d, cos_theta_max: f64) -> Vector3d {
+    let cos_theta = 1.0 - rng.gen::<f64>() * (1.0 - cos_theta_max);
+    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+    let phi = rng.gen_range(0.0, 2.0 * PI);
+    let helper = if axis.x.abs() > 0.9 {
+        Vector3d { x: 0.0, y: 1.0, z: 0.0 }
+    } else {
+        Vector3d { x: 1.0, y: 0.0, z: 0.0 }
+    };
+    let u = unit_vector(&cross(&helper, axis));
+    let v = cross(axis, &u);
+    u * (sin_theta * phi.cos()) + &(v * (sin_theta * phi.sin())) + &(*axis * cos_theta)
+}
+
 #[inline(always)]
 pub fn reflect(v: &Vector3d, n: &Vector3d) -> Vector3d {
     *v - &((*n * dot(v, n)) * 2.0_f64)
 }
 
+#[inline(always)]
+pub fn refract(uv: &Vector3d, n: &Vector3d, etai_over_etat: f64) -> Vector3d {
+    let cos_theta = (-dot(uv, n)).min(1.0);
+    let r_out_perp = (*uv + &(*n * cos_theta)) * etai_over_etat;
+    let r_out_parallel = *n * -(1.0 - r_out_perp.length_squared()).abs().sqrt();
+    r_out_perp + &r_out_parallel
+}
+
 #[inline(always)]
 pub fn random_in_unit_disk(rng: &mut StdRng) -> Vector3d {
     let mut p = Vector3d {
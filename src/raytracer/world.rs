@@ -1,49 +1,259 @@
-use crate::animation::animation::world_time_from_real_time;
+use serde::{Serialize, Serializer};
+
+use crate::raytracer::barnes_hut::accelerations_barnes_hut;
+use crate::raytracer::bvh::Bvh;
+use crate::raytracer::color::Color;
 use crate::raytracer::hit::Hit;
-use crate::raytracer::physics::{bounce, dim, friction, gravitate, move_positions, PhysicsSettings, solve_non_overlapping_constraint};
+use crate::raytracer::physics::{bounce, bounce_swept, dim, friction, gravity_accelerations, GravityApproximation, PhysicsSettings, rotate, solve_non_overlapping_constraint, touching};
 use crate::raytracer::ray::Ray;
 use crate::raytracer::sphere::Sphere;
 
 pub struct World {
-    pub spheres: Vec<Sphere>
+    spheres: Vec<Sphere>,
+    bvh: Bvh,
+    emitters: Vec<usize>,
 }
 
 impl World {
+    pub fn new(spheres: Vec<Sphere>) -> World {
+        let bvh = Bvh::new(&spheres);
+        let emitters = spheres.iter().enumerate()
+            .filter(|(_, s)| s.emission() != Color::black())
+            .map(|(i, _)| i)
+            .collect();
+        World { spheres, bvh, emitters }
+    }
+
+    /// Indices of all spheres emitting light
+    pub fn emitters(&self) -> &[usize] {
+        &self.emitters
+    }
+
+    pub fn spheres(&self) -> &[Sphere] {
+        &self.spheres
+    }
+
     pub fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<Hit> {
+        self.bvh.hit(&self.spheres, ray, t_min, t_max)
+    }
+
+    // Reference implementation for `hit`
+    #[cfg(test)]
+    fn hit_brute_force(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<Hit> {
         let mut closest_so_far = t_max;
         let mut rec: Option<Hit> = None;
-        for sphere in &self.spheres {
-            match sphere.hit(ray, t_min, closest_so_far) {
-                Some(temp_rec) => {
-                    closest_so_far = temp_rec.t;
-                    rec = Some(temp_rec);
-                }
-                None => {}
+        for (i, sphere) in self.spheres.iter().enumerate() {
+            if let Some(temp_rec) = sphere.hit(ray, t_min, closest_so_far) {
+                closest_so_far = temp_rec.t;
+                rec = Some(Hit { sphere_index: Some(i), ..temp_rec });
             }
         }
         rec
     }
 
-    pub fn advance(&self, t_real: f64, t_real_previous_frame: f64,
-                   physics: &PhysicsSettings) -> World {
-        let delta_t = world_time_from_real_time(t_real) -
-            world_time_from_real_time(t_real_previous_frame);
-        World {
-            spheres:
+    pub fn advance(&self, delta_t: f64, physics: &PhysicsSettings) -> World {
+        let acceleration = |spheres: &[Sphere]| {
+            match physics.gravity_approximation {
+                GravityApproximation::Exact =>
+                    gravity_accelerations(spheres, physics.gravity_constant),
+                GravityApproximation::BarnesHut { opening_angle } =>
+                    accelerations_barnes_hut(spheres, physics.gravity_constant, opening_angle)
+            }
+        };
+        let integrator = physics.integrator.integrator();
+        let substeps = physics.substeps.max(1);
+        let step_delta_t = delta_t / substeps as f64;
+        let spheres = (0..substeps).fold(self.spheres.clone(), |spheres, _| {
+            let moved = integrator.step(&spheres, step_delta_t, &acceleration);
+            let (moved, swept_bounced) = if physics.continuous_collision_detection {
+                bounce_swept(&moved, step_delta_t, physics.bounciness, physics.flash_strength,
+                             physics.bounce_round_to_zero_threshold, physics.contact_friction)
+            } else {
+                let no_bounces = vec![false; moved.len()];
+                (moved, no_bounces)
+            };
+            let rotated = rotate(&moved, step_delta_t);
+            let in_contact = touching(&rotated).iter().zip(swept_bounced.iter())
+                .map(|(touching, bounced)| *touching || *bounced)
+                .collect::<Vec<bool>>();
             dim(
                 &friction(
                     &solve_non_overlapping_constraint(
                         &bounce(
-                            &gravitate(
-                                &move_positions(
-                                    &self.spheres, delta_t),
-                                delta_t, physics.gravity_constant),
+                            &rotated,
                             physics.bounciness,
                             physics.flash_strength,
-                            physics.bounce_round_to_zero_threshold)
+                            physics.bounce_round_to_zero_threshold,
+                            physics.contact_friction)
                     ),
-                    delta_t, physics.friction),
-                delta_t, physics.dim_factor, physics.dim_constant)
+                    &in_contact, step_delta_t, physics.friction, physics.rolling_resistance),
+                step_delta_t, physics.dim_factor, physics.dim_constant)
+        });
+        // Motion blur covers the whole frame, not just the last substep.
+        World::new(spheres.iter().zip(self.spheres.iter()).map(|(sphere, old)| {
+            Sphere {
+                center_old: old.center,
+                orientation_old: old.orientation,
+                ..sphere.clone()
+            }
+        }).collect())
+    }
+}
+
+// Only the spheres are stored, the acceleration structures are rebuilt when loading.
+impl Serialize for World {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+    {
+        self.spheres.serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::prelude::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use crate::animation::animation::physics_settings;
+    use crate::raytracer::integrator::IntegratorKind;
+    use crate::raytracer::material::Material;
+    use crate::raytracer::quaternion::Quaternion;
+    use crate::raytracer::texture::Texture;
+    use crate::raytracer::vector3d::{random_unit_vector, Vector3d};
+
+    use super::*;
+
+    fn random_vector(rng: &mut StdRng, max: f64) -> Vector3d {
+        Vector3d {
+            x: rng.gen_range(-max, max),
+            y: rng.gen_range(-max, max),
+            z: rng.gen_range(-max, max),
+        }
+    }
+
+    fn sphere(center: Vector3d, radius: f64, speed: Vector3d) -> Sphere {
+        Sphere {
+            center,
+            radius,
+            material: Material {
+                albedo: Texture::Solid(Color::black()),
+                reflectiveness: 0.0,
+                reflection_fuzz: 0.0,
+                transparency: 0.0,
+                refraction_index: 1.5,
+                absorption: Color::black(),
+                emission: Color::black(),
+                flash_emission: 0.0,
+            },
+            speed,
+            mass: 1.0,
+            extra_brightness: 0.0,
+            center_old: center,
+            angular_velocity: Vector3d::null(),
+            orientation: Quaternion::identity(),
+            orientation_old: Quaternion::identity(),
+        }
+    }
+
+    #[test]
+    fn test_bvh_hit_equals_brute_force() {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(1);
+        let world = World::new((0..300).map(|_| {
+            let center = random_vector(&mut rng, 20.0);
+            Sphere {
+                center_old: center + &random_vector(&mut rng, 3.0),
+                ..sphere(center, rng.gen_range(0.1, 2.0), Vector3d::null())
+            }
+        }).collect());
+        for _ in 0..5000 {
+            let ray = Ray {
+                origin: random_vector(&mut rng, 30.0),
+                direction: random_unit_vector(&mut rng),
+                frame_time: rng.gen_range(0.0, 1.0),
+            };
+            let expected = world.hit_brute_force(&ray, 0.001, 1000.0).map(|h| h.position);
+            let result = world.hit(&ray, 0.001, 1000.0).map(|h| h.position);
+            assert_eq!(result, expected);
         }
     }
+
+    #[test]
+    fn test_rolling_resistance_only_slows_down_touching_spheres() {
+        let physics = PhysicsSettings { gravity_constant: 0.0, ..physics_settings() };
+        let spin = Vector3d { x: 0.0, y: 0.0, z: 3.0 };
+        let spinning = |center: Vector3d| Sphere {
+            angular_velocity: spin,
+            ..sphere(center, 1.0, Vector3d::null())
+        };
+        let world = World::new(vec![
+            spinning(Vector3d { x: -5.0, y: 0.0, z: 0.0 }),
+            spinning(Vector3d { x: 5.0, y: 0.0, z: 0.0 }),
+            sphere(Vector3d { x: 5.0, y: -1.99, z: 0.0 }, 1.0, Vector3d::null()),
+        ]);
+        let advanced = world.advance(0.1, &physics);
+        assert_eq!(advanced.spheres()[0].angular_velocity, spin);
+        assert!(advanced.spheres()[1].angular_velocity.z < 0.9 * spin.z);
+    }
+
+    #[test]
+    fn test_fast_spheres_do_not_tunnel() {
+        let physics = |continuous_collision_detection| PhysicsSettings {
+            integrator: IntegratorKind::ExplicitEuler,
+            substeps: 1,
+            gravity_constant: 0.0,
+            gravity_approximation: GravityApproximation::Exact,
+            bounciness: 1.0,
+            bounce_round_to_zero_threshold: 0.0,
+            flash_strength: 0.0,
+            dim_factor: 0.0,
+            dim_constant: 0.0,
+            friction: 0.0,
+            contact_friction: 0.0,
+            rolling_resistance: 0.0,
+            continuous_collision_detection,
+        };
+        // Each one moves 50 times its radius per step.
+        let world = World::new(vec![
+            sphere(Vector3d { x: -1.0, y: 0.0, z: 0.0 }, 0.1,
+                   Vector3d { x: 50.0, y: 0.0, z: 0.0 }),
+            sphere(Vector3d { x: 1.0, y: 0.0, z: 0.0 }, 0.1,
+                   Vector3d { x: -50.0, y: 0.0, z: 0.0 }),
+        ]);
+
+        let tunnelled = world.advance(0.1, &physics(false));
+        assert!(tunnelled.spheres()[0].center.x > 0.0 && tunnelled.spheres()[1].center.x < 0.0);
+
+        let bounced = world.advance(0.1, &physics(true));
+        let (a, b) = (&bounced.spheres()[0], &bounced.spheres()[1]);
+        assert_eq!((a.speed.x, b.speed.x), (-50.0, 50.0));
+        // Touching at x = -0.1 and x = 0.1 after 0.018 time units, then moving apart.
+        assert!((a.center.x - (-0.1 - 50.0 * 0.082)).abs() < 1e-9);
+        assert!((b.center.x - (0.1 + 50.0 * 0.082)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_continuous_collision_detection_keeps_other_spheres() {
+        let physics = |continuous_collision_detection| PhysicsSettings {
+            integrator: IntegratorKind::ExplicitEuler,
+            substeps: 1,
+            gravity_constant: 1.0,
+            continuous_collision_detection,
+            ..physics_settings()
+        };
+        // The first two tunnel through each other, the third one is only pulled by them.
+        let world = World::new(vec![
+            sphere(Vector3d { x: -1.0, y: 0.0, z: 0.0 }, 0.1,
+                   Vector3d { x: 50.0, y: 0.0, z: 0.0 }),
+            sphere(Vector3d { x: 1.0, y: 0.0, z: 0.0 }, 0.1,
+                   Vector3d { x: -50.0, y: 0.0, z: 0.0 }),
+            sphere(Vector3d { x: 0.0, y: 10.0, z: 0.0 }, 0.1,
+                   Vector3d { x: 1.0, y: 0.0, z: 0.0 }),
+        ]);
+        let without = world.advance(0.1, &physics(false));
+        let with = world.advance(0.1, &physics(true));
+        assert_ne!(with.spheres()[0].speed, without.spheres()[0].speed);
+        assert_eq!(with.spheres()[2].center, without.spheres()[2].center);
+        assert_eq!(with.spheres()[2].speed, without.spheres()[2].speed);
+    }
 }